@@ -0,0 +1,322 @@
+use crate::offer::{Offer, OfferStatus, OfferUID, OrderType};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+/// The offers resting at a single price, earliest first.
+pub(crate) type Level = VecDeque<(DateTime<Utc>, OfferUID)>;
+
+/// The combined size of the offers resting at a single price.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct PriceLevel {
+    pub price_per_item: u64,
+    pub item_amount: u64,
+    pub offer_count: usize,
+}
+
+/// Queues `offer_id` at `price` behind every offer placed before `datetime`,
+/// unless it is already queued there.
+fn enqueue(
+    side: &mut BTreeMap<u64, Level>,
+    price: u64,
+    datetime: DateTime<Utc>,
+    offer_id: OfferUID,
+) {
+    let level = side.entry(price).or_default();
+
+    if level.iter().any(|(_, id)| *id == offer_id) {
+        return;
+    }
+
+    // Offers are almost always newer than everything already queued, but
+    // reverted fills can put an older offer back.
+    let index = level.partition_point(|(queued, _)| *queued <= datetime);
+    level.insert(index, (datetime, offer_id));
+}
+
+/// Removes `offer_id` from the queue at `price`, dropping the level once it is
+/// empty.
+fn dequeue(side: &mut BTreeMap<u64, Level>, price: u64, offer_id: OfferUID) {
+    let Some(level) = side.get_mut(&price) else {
+        return;
+    };
+
+    level.retain(|(_, id)| *id != offer_id);
+
+    if level.is_empty() {
+        side.remove(&price);
+    }
+}
+
+/// How long trades are kept for a commodity's ticker.
+const TICKER_WINDOW_HOURS: i64 = 24;
+
+/// A summary of a commodity's trades since some point in time. `last_trade` is
+/// the time, price and item amount of its most recent trade, which may be from
+/// before then.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TradeStats {
+    pub last_trade: Option<(DateTime<Utc>, u64, u64)>,
+    pub volume: u64,
+    pub high: Option<u64>,
+    pub low: Option<u64>,
+    pub open: Option<u64>,
+}
+
+/// The live offers for a single commodity, grouped into price levels so that
+/// the best prices can be found without looking at every offer. The book is
+/// never saved, and is rebuilt from the offers whenever the market starts.
+///
+/// Dormant stop offers are kept apart from the visible book, queued by their
+/// stop price until the commodity's last trade price triggers them.
+///
+/// The prices of recent trades are kept alongside the book for the circuit
+/// breaker, until they are pruned. Trades from the last day are kept separately
+/// for the ticker.
+#[derive(Debug, Default, Clone)]
+pub struct OrderBook {
+    asks: BTreeMap<u64, Level>,
+    bids: BTreeMap<u64, Level>,
+    sell_stops: BTreeMap<u64, Level>,
+    buy_stops: BTreeMap<u64, Level>,
+    last_price: Option<u64>,
+    recent_prices: VecDeque<(DateTime<Utc>, u64, u64)>,
+    daily_trades: VecDeque<(DateTime<Utc>, u64, u64)>,
+}
+
+impl OrderBook {
+    fn get_side_mut(&mut self, offer: &Offer) -> &mut BTreeMap<u64, Level> {
+        if offer.is_ask() {
+            &mut self.asks
+        } else {
+            &mut self.bids
+        }
+    }
+
+    fn get_stop_side_mut(&mut self, offer: &Offer) -> &mut BTreeMap<u64, Level> {
+        if offer.is_ask() {
+            &mut self.sell_stops
+        } else {
+            &mut self.buy_stops
+        }
+    }
+
+    /// The price of the commodity's most recent trade.
+    pub(crate) fn get_last_price(&self) -> Option<u64> { self.last_price }
+
+    /// Records a trade of `item_amount` items at `price`, making it the last
+    /// trade price.
+    pub(crate) fn record_price(
+        &mut self,
+        datetime: DateTime<Utc>,
+        price: u64,
+        item_amount: u64,
+    ) {
+        self.last_price = Some(price);
+        self.recent_prices.push_back((datetime, price, item_amount));
+        self.daily_trades.push_back((datetime, price, item_amount));
+
+        let since = datetime - Duration::hours(TICKER_WINDOW_HOURS);
+
+        while self
+            .daily_trades
+            .front()
+            .is_some_and(|(datetime, ..)| *datetime < since)
+        {
+            self.daily_trades.pop_front();
+        }
+    }
+
+    /// Removes a trade which failed to settle from the ticker's figures.
+    pub(crate) fn forget_trade(
+        &mut self,
+        datetime: DateTime<Utc>,
+        price: u64,
+        item_amount: u64,
+    ) {
+        if let Some(index) = self
+            .daily_trades
+            .iter()
+            .position(|trade| *trade == (datetime, price, item_amount))
+        {
+            self.daily_trades.remove(index);
+        }
+    }
+
+    /// Summarises the trades since `since`, which are only kept for the last
+    /// day.
+    pub(crate) fn get_trade_stats(&self, since: DateTime<Utc>) -> TradeStats {
+        let mut stats = TradeStats {
+            last_trade: self.daily_trades.back().copied(),
+            ..TradeStats::default()
+        };
+
+        for (_, price, item_amount) in self
+            .daily_trades
+            .iter()
+            .filter(|(datetime, ..)| *datetime >= since)
+        {
+            stats.volume = stats.volume.saturating_add(*item_amount);
+            stats.high = stats.high.max(Some(*price));
+            stats.low = Some(stats.low.map_or(*price, |low| low.min(*price)));
+            stats.open = stats.open.or(Some(*price));
+        }
+
+        stats
+    }
+
+    /// Forgets the prices of trades from before `since`.
+    pub(crate) fn prune_prices(&mut self, since: DateTime<Utc>) {
+        self.recent_prices
+            .retain(|(datetime, ..)| *datetime >= since);
+    }
+
+    /// Forgets the prices of every recent trade, but not the last trade price.
+    pub(crate) fn clear_recent_prices(&mut self) { self.recent_prices.clear(); }
+
+    /// The lowest and highest prices traded since `since`.
+    pub(crate) fn get_price_range(&self, since: DateTime<Utc>) -> Option<(u64, u64)> {
+        let prices = self
+            .recent_prices
+            .iter()
+            .filter(|(datetime, ..)| *datetime >= since)
+            .map(|(_, price, _)| *price);
+
+        prices.clone().min().zip(prices.max())
+    }
+
+    /// The average price traded since `since`, weighted by the number of items
+    /// traded.
+    pub(crate) fn get_average_price(&self, since: DateTime<Utc>) -> Option<u64> {
+        let (value, item_amount) = self
+            .recent_prices
+            .iter()
+            .filter(|(datetime, ..)| *datetime >= since)
+            .fold(
+                (0_u128, 0_u128),
+                |(value, items), (_, price, item_amount)| {
+                    (
+                        value + u128::from(*price) * u128::from(*item_amount),
+                        items + u128::from(*item_amount),
+                    )
+                },
+            );
+
+        value
+            .checked_div(item_amount)
+            .and_then(|average| u64::try_from(average).ok())
+    }
+
+    /// The ask levels, lowest price first.
+    pub(crate) fn get_ask_levels(&self) -> impl Iterator<Item = (u64, &Level)> {
+        self.asks
+            .iter()
+            .map(|(price_per_item, level)| (*price_per_item, level))
+    }
+
+    /// The bid levels, highest price first.
+    pub(crate) fn get_bid_levels(&self) -> impl Iterator<Item = (u64, &Level)> {
+        self.bids
+            .iter()
+            .rev()
+            .map(|(price_per_item, level)| (*price_per_item, level))
+    }
+
+    /// Queues `offer` at its price level behind every offer placed before it,
+    /// or with the other stops at its stop price if it is dormant. Offers
+    /// which are already in the book are left where they are.
+    pub(crate) fn insert(&mut self, offer_id: OfferUID, offer: &Offer) {
+        let datetime = offer.get_datetime();
+
+        match offer.get_options().stop_price {
+            Some(stop_price) if offer.get_status() == OfferStatus::Dormant => enqueue(
+                self.get_stop_side_mut(offer),
+                stop_price,
+                datetime,
+                offer_id,
+            ),
+            _ => enqueue(
+                self.get_side_mut(offer),
+                offer.get_price_per_item(),
+                datetime,
+                offer_id,
+            ),
+        }
+    }
+
+    /// Removes `offer` from the book, whether it is resting or dormant.
+    pub(crate) fn remove(&mut self, offer_id: OfferUID, offer: &Offer) {
+        dequeue(
+            self.get_side_mut(offer),
+            offer.get_price_per_item(),
+            offer_id,
+        );
+
+        if let Some(stop_price) = offer.get_options().stop_price {
+            dequeue(self.get_stop_side_mut(offer), stop_price, offer_id);
+        }
+    }
+
+    /// Takes every dormant stop offer which the last trade price has triggered
+    /// out of the book, earliest first.
+    pub(crate) fn take_triggered(&mut self) -> Vec<OfferUID> {
+        let Some(last_price) = self.last_price else {
+            return Vec::new();
+        };
+
+        let sell_prices = self
+            .sell_stops
+            .range(last_price..)
+            .map(|(price, _)| *price)
+            .collect::<Vec<_>>();
+        let buy_prices = self
+            .buy_stops
+            .range(..=last_price)
+            .map(|(price, _)| *price)
+            .collect::<Vec<_>>();
+
+        let mut triggered = sell_prices
+            .iter()
+            .filter_map(|price| self.sell_stops.remove(price))
+            .chain(
+                buy_prices
+                    .iter()
+                    .filter_map(|price| self.buy_stops.remove(price)),
+            )
+            .flatten()
+            .collect::<Vec<_>>();
+
+        triggered.sort_by_key(|(datetime, _)| *datetime);
+
+        triggered
+            .into_iter()
+            .map(|(_, offer_id)| offer_id)
+            .collect()
+    }
+
+    /// The offers on the other side of the book which `offer` can trade
+    /// against, best price first (lowest ask, highest bid) and then earliest
+    /// first.
+    pub(crate) fn crossing<'a>(
+        &'a self,
+        offer: &Offer,
+    ) -> impl Iterator<Item = OfferUID> + 'a {
+        let price_per_item = offer.get_price_per_item();
+        let is_market = offer.get_order_type() == OrderType::Market;
+
+        let levels: Box<dyn Iterator<Item = &Level>> = match (offer.is_ask(), is_market) {
+            (true, true) => Box::new(self.bids.values().rev()),
+            (true, false) => Box::new(
+                self.bids
+                    .range(price_per_item..)
+                    .rev()
+                    .map(|(_, level)| level),
+            ),
+            (false, true) => Box::new(self.asks.values()),
+            (false, false) =>
+                Box::new(self.asks.range(..=price_per_item).map(|(_, level)| level)),
+        };
+
+        levels.flat_map(|level| level.iter().map(|(_, offer_id)| *offer_id))
+    }
+}
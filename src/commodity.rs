@@ -1,5 +1,10 @@
-use crate::user::UserUID;
-use dashmap::DashSet;
+use crate::{
+    offer::{Offer, OrderType},
+    state::StateError,
+    user::UserUID,
+};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Utc, Weekday};
+use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use uuid::Uuid;
@@ -11,39 +16,571 @@ impl fmt::Display for CommodityUID {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}", self.0) }
 }
 
+/// How offers for a commodity are matched. Continuous commodities match each
+/// offer as soon as it is placed, while auction commodities collect offers and
+/// match them all at once every `interval_seconds`, at the single price which
+/// trades the most items.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum TradingMode {
+    #[default]
+    Continuous,
+    Auction {
+        interval_seconds: u64,
+    },
+}
+
+/// Limits on the offers which can be placed for a commodity, so that the book
+/// isn't fragmented across odd prices and sizes. Prices must be a multiple of
+/// `tick_size` and quantities a multiple of `lot_size`. The order value limits
+/// apply to the total price of limit offers.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct TradingRules {
+    pub tick_size: u64,
+    pub lot_size: u64,
+    pub min_order_value: Option<u64>,
+    pub max_order_value: Option<u64>,
+}
+
+impl Default for TradingRules {
+    fn default() -> Self {
+        Self {
+            tick_size: 1,
+            lot_size: 1,
+            min_order_value: None,
+            max_order_value: None,
+        }
+    }
+}
+
+impl TradingRules {
+    /// Checks that the rules themselves make sense.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.tick_size == 0 {
+            return Err("tick_size cannot be 0".into());
+        }
+
+        if self.lot_size == 0 {
+            return Err("lot_size cannot be 0".into());
+        }
+
+        match (self.min_order_value, self.max_order_value) {
+            (Some(min), Some(max)) if min > max => Err(format!(
+                "min_order_value ({min}) cannot be above max_order_value ({max})"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_price(&self, name: &str, price: u64) -> Result<(), StateError> {
+        if price % self.tick_size != 0 {
+            return Err(StateError::TickSize(format!(
+                "{name} ({price}) must be a multiple of the tick size ({})",
+                self.tick_size
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn check_amount(&self, name: &str, amount: u64) -> Result<(), StateError> {
+        if amount % self.lot_size != 0 {
+            return Err(StateError::LotSize(format!(
+                "{name} ({amount}) must be a multiple of the lot size ({})",
+                self.lot_size
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Checks a new offer against the rules. Market offers have no price, so
+    /// only their quantities are checked.
+    pub(crate) fn check_offer(&self, offer: &Offer) -> Result<(), StateError> {
+        let options = offer.get_options();
+        let item_amount = offer.get_remaining_amount();
+
+        self.check_amount("Item amount", item_amount)?;
+
+        if let Some(display_amount) = options.display_amount {
+            self.check_amount("display_amount", display_amount)?;
+        }
+
+        if let Some(stop_price) = options.stop_price {
+            self.check_price("stop_price", stop_price)?;
+        }
+
+        if offer.get_order_type() == OrderType::Limit {
+            let price_per_item = offer.get_price_per_item();
+
+            self.check_price("Price per item", price_per_item)?;
+            self.check_value(item_amount, price_per_item)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the total price of `item_amount` items at `price_per_item` each
+    /// against the order value limits.
+    pub(crate) fn check_value(
+        &self,
+        item_amount: u64,
+        price_per_item: u64,
+    ) -> Result<(), StateError> {
+        let value = item_amount.saturating_mul(price_per_item);
+
+        if let Some(min) = self.min_order_value.filter(|min| value < *min) {
+            return Err(StateError::OrderValue(format!(
+                "Order value ({value} CSH) is below the minimum of {min} CSH"
+            )));
+        }
+
+        if let Some(max) = self.max_order_value.filter(|max| value > *max) {
+            return Err(StateError::OrderValue(format!(
+                "Order value ({value} CSH) is above the maximum of {max} CSH"
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// The price which offers are compared against by the price band.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReferencePrice {
+    #[default]
+    LastTrade,
+    /// The average price traded over the circuit breaker's window, weighted by
+    /// the number of items traded.
+    MovingAverage,
+}
+
+/// Protection against outlier prices for a commodity. Limit offers priced more
+/// than `price_band_percent` away from the reference price are rejected, and
+/// trading is halted if a trade's price moves more than `halt_move_percent`
+/// away from any other price traded in the last `window_seconds`. Halts last
+/// for `halt_seconds`, or until trading is resumed by hand if that isn't set.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct CircuitBreaker {
+    pub reference_price: ReferencePrice,
+    pub price_band_percent: Option<u64>,
+    pub halt_move_percent: Option<u64>,
+    pub window_seconds: u64,
+    pub halt_seconds: Option<u64>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            reference_price: ReferencePrice::default(),
+            price_band_percent: None,
+            halt_move_percent: None,
+            window_seconds: 300,
+            halt_seconds: None,
+        }
+    }
+}
+
+/// The longest window or halt the circuit breaker can be set to, a year.
+const MAX_BREAKER_SECONDS: u64 = 365 * 24 * 60 * 60;
+
+/// Whether `price` is more than `percent`% away from `reference`.
+fn is_outside(price: u64, reference: u64, percent: u64) -> bool {
+    u128::from(price.abs_diff(reference)) * 100
+        > u128::from(reference) * u128::from(percent)
+}
+
+impl CircuitBreaker {
+    /// Checks that the settings themselves make sense.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.window_seconds == 0 || self.window_seconds > MAX_BREAKER_SECONDS {
+            return Err(format!(
+                "window_seconds must be between 1 and {MAX_BREAKER_SECONDS}"
+            ));
+        }
+
+        if self.price_band_percent == Some(0) {
+            return Err("price_band_percent cannot be 0".into());
+        }
+
+        if self.halt_move_percent == Some(0) {
+            return Err("halt_move_percent cannot be 0".into());
+        }
+
+        if self
+            .halt_seconds
+            .is_some_and(|seconds| seconds == 0 || seconds > MAX_BREAKER_SECONDS)
+        {
+            return Err(format!(
+                "halt_seconds must be between 1 and {MAX_BREAKER_SECONDS}"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// When the window of recent trades the breaker looks at starts.
+    pub(crate) fn get_window_start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let window = self.window_seconds.min(MAX_BREAKER_SECONDS);
+
+        now - Duration::seconds(i64::try_from(window).unwrap_or_default())
+    }
+
+    /// Checks `price` against the price band around `reference`.
+    pub(crate) fn check_band(
+        &self,
+        price: u64,
+        reference: u64,
+    ) -> Result<(), StateError> {
+        match self.price_band_percent {
+            Some(percent) if is_outside(price, reference, percent) =>
+                Err(StateError::PriceBand(format!(
+                    "Price ({price} CSH) is more than {percent}% away from the \
+                     reference price ({reference} CSH)"
+                ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// The price in `range`, the lowest and highest recent prices, which
+    /// `price` has moved too far away from, if any.
+    pub(crate) fn get_breached_price(
+        &self,
+        price: u64,
+        range: (u64, u64),
+    ) -> Option<u64> {
+        let percent = self.halt_move_percent?;
+        let (low, high) = range;
+
+        [low, high]
+            .into_iter()
+            .find(|reference| is_outside(price, *reference, percent))
+    }
+}
+
+/// Why and since when trading in a commodity has been halted.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Halt {
+    pub reason: String,
+    pub halted_at: DateTime<Utc>,
+    pub resumes_at: Option<DateTime<Utc>>,
+}
+
+/// Where a commodity is in its trading day. Offers can be placed while it is
+/// in pre-open but nothing trades until it opens.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TradingSession {
+    Closed,
+    PreOpen,
+    #[default]
+    Open,
+}
+
+/// When a commodity trades: between `open_time` and `close_time` (UTC) on each
+/// of `days`, with a pre-open of `pre_open_minutes` before each opening.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TradingSchedule {
+    pub days: Vec<Weekday>,
+    pub open_time: NaiveTime,
+    pub close_time: NaiveTime,
+    #[serde(default)]
+    pub pre_open_minutes: u32,
+}
+
+impl TradingSchedule {
+    /// Checks that the schedule itself makes sense.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.days.is_empty() {
+            return Err("days cannot be empty".into());
+        }
+
+        if self.open_time >= self.close_time {
+            return Err("open_time must be before close_time".into());
+        }
+
+        if self.pre_open_minutes > 24 * 60 {
+            return Err("pre_open_minutes cannot be more than a day".into());
+        }
+
+        Ok(())
+    }
+
+    /// The session the schedule puts a commodity in at `now`.
+    pub(crate) fn get_session(&self, now: DateTime<Utc>) -> TradingSession {
+        let now = now.naive_utc();
+        let is_trading_day = |date: NaiveDate| self.days.contains(&date.weekday());
+
+        if is_trading_day(now.date())
+            && self.open_time <= now.time()
+            && now.time() < self.close_time
+        {
+            return TradingSession::Open;
+        }
+
+        // Pre-open can start the day before an early opening.
+        let pre_open = Duration::minutes(i64::from(self.pre_open_minutes));
+        let is_pre_open = [now.date(), now.date() + Duration::days(1)]
+            .into_iter()
+            .filter(|date| is_trading_day(*date))
+            .map(|date| date.and_time(self.open_time))
+            .any(|open| open - pre_open <= now && now < open);
+
+        if is_pre_open {
+            TradingSession::PreOpen
+        } else {
+            TradingSession::Closed
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Commodity {
     name: String,
     size: u64,
-    owners: DashSet<UserUID>,
+    /// How many items of this commodity each user holds.
+    #[serde(default)]
+    holdings: DashMap<UserUID, u64>,
+    /// How many of each user's held items are locked by their open asks.
+    #[serde(default)]
+    reserved: DashMap<UserUID, u64>,
+    #[serde(default)]
+    trading_mode: TradingMode,
+    #[serde(default)]
+    trading_rules: TradingRules,
+    #[serde(default)]
+    circuit_breaker: CircuitBreaker,
+    #[serde(default)]
+    halt: Option<Halt>,
+    #[serde(default)]
+    schedule: Option<TradingSchedule>,
+    #[serde(default)]
+    session: TradingSession,
+    /// When this commodity's last auction was run, if it is auctioned.
+    #[serde(default)]
+    last_auction_at: Option<DateTime<Utc>>,
 }
 
 impl Commodity {
+    /// Creates a new commodity, issuing `initial_amount` items to `owner_id`.
     pub(crate) fn new(
         name: &str,
         initial_amount: Option<u64>,
-        owner_ids: Option<DashSet<UserUID>>,
+        owner_id: Option<UserUID>,
     ) -> Self {
         let size = initial_amount.unwrap_or(0);
-        let owners = owner_ids.unwrap_or(DashSet::new());
+        let holdings = DashMap::new();
+
+        if let Some(owner_id) = owner_id.filter(|_| size > 0) {
+            holdings.insert(owner_id, size);
+        }
 
         Self {
             name: name.to_owned(),
             size,
-            owners,
+            holdings,
+            reserved: DashMap::new(),
+            trading_mode: TradingMode::default(),
+            trading_rules: TradingRules::default(),
+            circuit_breaker: CircuitBreaker::default(),
+            halt: None,
+            schedule: None,
+            session: TradingSession::default(),
+            last_auction_at: None,
         }
     }
 
     pub(crate) fn get_owner_ids(&self) -> Vec<UserUID> {
-        self.owners
+        self.holdings
             .iter()
+            .filter(|item| *item.value() > 0)
             .map(|item| *item.key())
             .collect::<Vec<_>>()
     }
 
     pub(crate) fn get_name(&self) -> &str { &self.name }
 
-    pub(crate) fn add_owner_id(&mut self, user_id: UserUID) {
-        self.owners.insert(user_id);
+    pub(crate) fn get_trading_mode(&self) -> TradingMode { self.trading_mode }
+
+    pub(crate) fn get_trading_rules(&self) -> TradingRules { self.trading_rules }
+
+    pub(crate) fn set_trading_rules(&mut self, trading_rules: TradingRules) {
+        self.trading_rules = trading_rules;
+    }
+
+    pub(crate) fn get_circuit_breaker(&self) -> CircuitBreaker { self.circuit_breaker }
+
+    pub(crate) fn set_circuit_breaker(&mut self, circuit_breaker: CircuitBreaker) {
+        self.circuit_breaker = circuit_breaker;
+    }
+
+    pub(crate) fn get_halt(&self) -> Option<&Halt> { self.halt.as_ref() }
+
+    pub(crate) fn is_halted(&self) -> bool { self.halt.is_some() }
+
+    /// Halts trading, until `halt_seconds` from `now` if the circuit breaker
+    /// sets it.
+    pub(crate) fn halt(&mut self, reason: String, now: DateTime<Utc>) {
+        let resumes_at = self.circuit_breaker.halt_seconds.map(|seconds| {
+            let seconds = seconds.min(MAX_BREAKER_SECONDS);
+
+            now + Duration::seconds(i64::try_from(seconds).unwrap_or_default())
+        });
+
+        self.halt = Some(Halt {
+            reason,
+            halted_at: now,
+            resumes_at,
+        });
+    }
+
+    /// Lifts the halt on trading. Returns `false` if trading wasn't halted.
+    pub(crate) fn resume(&mut self) -> bool { self.halt.take().is_some() }
+
+    /// Whether a halt is due to be lifted at `now`.
+    pub(crate) fn is_resume_due(&self, now: DateTime<Utc>) -> bool {
+        self.halt
+            .as_ref()
+            .and_then(|halt| halt.resumes_at)
+            .is_some_and(|resumes_at| resumes_at <= now)
+    }
+
+    pub(crate) fn get_schedule(&self) -> Option<&TradingSchedule> {
+        self.schedule.as_ref()
+    }
+
+    pub(crate) fn set_schedule(&mut self, schedule: Option<TradingSchedule>) {
+        self.schedule = schedule;
+    }
+
+    pub(crate) fn get_session(&self) -> TradingSession { self.session }
+
+    /// Moves the commodity into the session its schedule puts it in at `now`.
+    /// Commodities without a schedule are always open. Returns whether the
+    /// commodity has just opened.
+    pub(crate) fn update_session(&mut self, now: DateTime<Utc>) -> bool {
+        let session = self
+            .schedule
+            .as_ref()
+            .map_or(TradingSession::Open, |schedule| schedule.get_session(now));
+        let opened =
+            session == TradingSession::Open && self.session != TradingSession::Open;
+
+        self.session = session;
+
+        opened
+    }
+
+    pub(crate) fn is_auction(&self) -> bool {
+        matches!(self.trading_mode, TradingMode::Auction { .. })
+    }
+
+    /// Switches the trading mode. A commodity switched to auctions has its
+    /// first auction one interval from `now`.
+    pub(crate) fn set_trading_mode(
+        &mut self,
+        trading_mode: TradingMode,
+        now: DateTime<Utc>,
+    ) {
+        self.trading_mode = trading_mode;
+        self.last_auction_at = self.is_auction().then_some(now);
+    }
+
+    /// Whether an auction is due to be run at `now`. Halted commodities, and
+    /// those outside of their trading hours, don't hold auctions.
+    pub(crate) fn is_auction_due(&self, now: DateTime<Utc>) -> bool {
+        let TradingMode::Auction { interval_seconds } = self.trading_mode else {
+            return false;
+        };
+
+        if self.is_halted() || self.session != TradingSession::Open {
+            return false;
+        }
+
+        match self.last_auction_at {
+            Some(last_auction_at) => {
+                let elapsed = (now - last_auction_at).num_seconds();
+
+                u64::try_from(elapsed).unwrap_or(0) >= interval_seconds
+            },
+            None => true,
+        }
+    }
+
+    pub(crate) fn set_last_auction(&mut self, now: DateTime<Utc>) {
+        self.last_auction_at = Some(now);
+    }
+
+    pub(crate) fn get_holding(&self, user_id: UserUID) -> u64 {
+        self.holdings.get(&user_id).map_or(0, |amount| *amount)
+    }
+
+    pub(crate) fn get_reserved(&self, user_id: UserUID) -> u64 {
+        self.reserved.get(&user_id).map_or(0, |amount| *amount)
+    }
+
+    /// The items `user_id` holds which aren't locked by an open ask.
+    pub(crate) fn get_available(&self, user_id: UserUID) -> u64 {
+        self.get_holding(user_id)
+            .saturating_sub(self.get_reserved(user_id))
+    }
+
+    /// Locks `amount` of `user_id`'s items for an ask. Returns `false` without
+    /// locking anything if they don't have enough available.
+    pub(crate) fn reserve(&mut self, user_id: UserUID, amount: u64) -> bool {
+        if self.get_available(user_id) < amount {
+            return false;
+        }
+
+        *self.reserved.entry(user_id).or_insert(0) += amount;
+
+        true
+    }
+
+    /// Unlocks `amount` of `user_id`'s reserved items.
+    pub(crate) fn release(&mut self, user_id: UserUID, amount: u64) {
+        if let Some(mut reserved) = self.reserved.get_mut(&user_id) {
+            *reserved = reserved.saturating_sub(amount);
+        }
+    }
+
+    /// Issues `amount` new items to `user_id`, growing the commodity. Returns
+    /// `false` without issuing anything if the commodity would grow too large.
+    pub(crate) fn issue(&mut self, user_id: UserUID, amount: u64) -> bool {
+        let Some(size) = self.size.checked_add(amount) else {
+            return false;
+        };
+
+        let mut holding = self.holdings.entry(user_id).or_insert(0);
+
+        let Some(new_holding) = holding.checked_add(amount) else {
+            return false;
+        };
+
+        *holding = new_holding;
+        self.size = size;
+
+        true
+    }
+
+    /// Moves `amount` of `from`'s reserved items to `to` once a trade has been
+    /// paid for. Returns `false` without moving anything if `from` doesn't hold
+    /// enough.
+    pub(crate) fn transfer(&mut self, from: UserUID, to: UserUID, amount: u64) -> bool {
+        if self.get_holding(from) < amount {
+            return false;
+        }
+
+        if let Some(mut holding) = self.holdings.get_mut(&from) {
+            *holding -= amount;
+        }
+
+        self.release(from, amount);
+        *self.holdings.entry(to).or_insert(0) += amount;
+
+        true
     }
 }
@@ -1,6 +1,19 @@
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 
+/// What the matcher does when an offer would trade against another offer from
+/// the same user. The newest offer is the one being matched, and the oldest is
+/// the one resting in the book.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+pub(crate) enum SelfTradePrevention {
+    #[default]
+    CancelNewest,
+    CancelOldest,
+    CancelBoth,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub(crate) struct Config {
     host: Cow<'static, str>,
@@ -8,6 +21,8 @@ pub(crate) struct Config {
     ledger_host: Option<String>,
     market_username: String,
     market_password: String,
+    #[serde(default)]
+    self_trade_prevention: SelfTradePrevention,
 }
 
 impl Config {
@@ -31,6 +46,9 @@ impl Config {
     pub(crate) fn get_ledger_host(&self) -> Option<&String> { self.ledger_host.as_ref() }
     pub(crate) fn get_market_username(&self) -> &str { &self.market_username }
     pub(crate) fn get_market_password(&self) -> &str { &self.market_password }
+    pub(crate) fn get_self_trade_prevention(&self) -> SelfTradePrevention {
+        self.self_trade_prevention
+    }
 }
 
 impl Default for Config {
@@ -41,6 +59,7 @@ impl Default for Config {
             ledger_host: None,
             market_username: "market".into(),
             market_password: "PLEASE CHANGE".into(),
+            self_trade_prevention: SelfTradePrevention::default(),
         }
     }
 }
@@ -0,0 +1,210 @@
+use crate::state::StateError;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fmt::Display;
+
+/// A machine-readable description of what went wrong, which also decides the
+/// status code an error is sent with.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    InvalidJson,
+    InvalidPath,
+    InvalidQuery,
+    IncompletePayload,
+    IncompleteLegacyPayload,
+    InconsistentLegacyPayload,
+    ConflictingPayload,
+    MissingSize,
+    InvalidSize,
+    InvalidSettings,
+    InvalidAmendment,
+    InvalidIssuance,
+    Unauthorized,
+    Forbidden,
+    RouteNotFound,
+    UserNotFound,
+    CommodityNotFound,
+    OfferNotFound,
+    OfferClosed,
+    TradingHalted,
+    TradingClosed,
+    TradingNotHalted,
+    InsufficientHoldings,
+    PreOpenRestricted,
+    AuctionRestricted,
+    TickSize,
+    LotSize,
+    OrderValue,
+    PriceBand,
+    PostOnlyWouldTrade,
+    NoLiquidity,
+    EscrowFailed,
+    LedgerUnavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn get_status(self) -> StatusCode {
+        match self {
+            Self::InvalidRequest
+            | Self::InvalidJson
+            | Self::InvalidPath
+            | Self::InvalidQuery
+            | Self::IncompletePayload
+            | Self::IncompleteLegacyPayload
+            | Self::InconsistentLegacyPayload
+            | Self::ConflictingPayload
+            | Self::MissingSize
+            | Self::InvalidSize
+            | Self::InvalidSettings
+            | Self::InvalidAmendment
+            | Self::InvalidIssuance => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::EscrowFailed => StatusCode::PAYMENT_REQUIRED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::RouteNotFound
+            | Self::UserNotFound
+            | Self::CommodityNotFound
+            | Self::OfferNotFound => StatusCode::NOT_FOUND,
+            Self::OfferClosed
+            | Self::TradingHalted
+            | Self::TradingClosed
+            | Self::TradingNotHalted => StatusCode::CONFLICT,
+            Self::InsufficientHoldings
+            | Self::PreOpenRestricted
+            | Self::AuctionRestricted
+            | Self::TickSize
+            | Self::LotSize
+            | Self::OrderValue
+            | Self::PriceBand
+            | Self::PostOnlyWouldTrade
+            | Self::NoLiquidity => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::LedgerUnavailable => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+/// An error returned from any route. It is sent as a JSON object holding its
+/// `code` and `message`, along with any extra fields describing what caused it.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    code: ErrorCode,
+    message: String,
+    details: Map<String, Value>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: Map::new(),
+        }
+    }
+
+    /// Adds `key` to the error's body alongside its code and message.
+    #[must_use]
+    pub fn with_detail(mut self, key: &str, value: impl Serialize) -> Self {
+        self.details.insert(
+            key.to_owned(),
+            serde_json::to_value(value).unwrap_or_default(),
+        );
+
+        self
+    }
+
+    pub fn invalid_request(message: impl Display) -> Self {
+        Self::new(ErrorCode::InvalidRequest, message.to_string())
+    }
+
+    pub fn escrow_failed(amount: u64, reason: impl Display) -> Self {
+        Self::new(
+            ErrorCode::EscrowFailed,
+            format!("Could not escrow {amount} CSH: {reason}"),
+        )
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Forbidden, message)
+    }
+
+    pub fn user_not_found(username: impl Display) -> Self {
+        Self::new(
+            ErrorCode::UserNotFound,
+            format!("User \"{username}\" not found"),
+        )
+    }
+
+    pub fn commodity_not_found(commodity: impl Display) -> Self {
+        Self::new(
+            ErrorCode::CommodityNotFound,
+            format!("Commodity \"{commodity}\" not found"),
+        )
+    }
+
+    pub fn offer_not_found(offer_id: impl Display) -> Self {
+        Self::new(
+            ErrorCode::OfferNotFound,
+            format!("Offer \"{offer_id}\" not found"),
+        )
+    }
+}
+
+impl From<StateError> for ApiError {
+    fn from(error: StateError) -> Self {
+        let code = match &error {
+            StateError::CommodityNotFound(_) => ErrorCode::CommodityNotFound,
+            StateError::OfferNotFound(_) => ErrorCode::OfferNotFound,
+            StateError::NotOfferOwner(_) => ErrorCode::Forbidden,
+            StateError::OfferClosed(..) => ErrorCode::OfferClosed,
+            StateError::InvalidSettings(_) => ErrorCode::InvalidSettings,
+            StateError::InvalidAmendment(_) => ErrorCode::InvalidAmendment,
+            StateError::InvalidIssuance(_) => ErrorCode::InvalidIssuance,
+            StateError::InsufficientHoldings { .. } => ErrorCode::InsufficientHoldings,
+            StateError::TradingHalted { .. } => ErrorCode::TradingHalted,
+            StateError::TradingClosed(_) => ErrorCode::TradingClosed,
+            StateError::PreOpenRestricted(_) => ErrorCode::PreOpenRestricted,
+            StateError::TickSize(_) => ErrorCode::TickSize,
+            StateError::LotSize(_) => ErrorCode::LotSize,
+            StateError::OrderValue(_) => ErrorCode::OrderValue,
+            StateError::PriceBand(_) => ErrorCode::PriceBand,
+            StateError::AuctionRestricted => ErrorCode::AuctionRestricted,
+            StateError::PostOnlyWouldTrade => ErrorCode::PostOnlyWouldTrade,
+            StateError::NotHalted(_) => ErrorCode::TradingNotHalted,
+        };
+
+        let api_error = Self::new(code, error.to_string());
+
+        match error {
+            StateError::InsufficientHoldings {
+                item_amount,
+                available,
+                ..
+            } => api_error
+                .with_detail("item_amount", item_amount)
+                .with_detail("available", available),
+            _ => api_error,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut body = self.details;
+        body.insert(
+            "code".to_owned(),
+            serde_json::to_value(self.code).unwrap_or_default(),
+        );
+        body.insert("message".to_owned(), Value::String(self.message));
+
+        (self.code.get_status(), Json(body)).into_response()
+    }
+}
@@ -2,12 +2,16 @@
 #![warn(clippy::pedantic)]
 #![allow(clippy::unused_async, clippy::module_name_repetitions)]
 
+mod book;
 mod commodity;
 mod config;
+mod error;
+mod extract;
 mod offer;
 mod router;
 mod routes;
 mod state;
+mod trade;
 mod user;
 
 use crate::{router::Router, state::AppState};
@@ -17,13 +21,13 @@ use chrono::Utc;
 use config::Config;
 use directories::ProjectDirs;
 use parking_lot::RwLock;
-use state::GState;
+use state::{release_offer, settle_pending_trades, settle_trades, GState, Matches};
 use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
 use tokio::{
     fs::{create_dir_all, File},
     io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
     runtime::Handle,
-    time::{interval_at, Instant},
+    time::{interval, interval_at, Instant},
 };
 use tracing_subscriber::{filter, prelude::*};
 
@@ -180,6 +184,93 @@ async fn background_save(state: GState) {
     }
 }
 
+async fn background_expire(state: GState) {
+    const SECONDS: u64 = 10;
+
+    tracing::info!("Expiring good-till-date offers every {SECONDS} seconds.");
+
+    let mut interval = interval(Duration::from_secs(SECONDS));
+
+    loop {
+        interval.tick().await;
+
+        let expired = state.write().expire_offers(Utc::now());
+
+        for offer in &expired {
+            release_offer(&state, offer).await;
+        }
+    }
+}
+
+async fn background_auction(state: GState) {
+    const SECONDS: u64 = 1;
+
+    tracing::info!("Checking for due commodity auctions every {SECONDS} second(s).");
+
+    let mut interval = interval(Duration::from_secs(SECONDS));
+
+    loop {
+        interval.tick().await;
+
+        let Matches {
+            trade_ids,
+            released,
+        } = state.write().run_due_auctions(Utc::now());
+
+        settle_trades(&state, &trade_ids).await;
+
+        for offer in &released {
+            release_offer(&state, offer).await;
+        }
+    }
+}
+
+async fn background_resume(state: GState) {
+    const SECONDS: u64 = 1;
+
+    tracing::info!("Checking for halts to lift every {SECONDS} second(s).");
+
+    let mut interval = interval(Duration::from_secs(SECONDS));
+
+    loop {
+        interval.tick().await;
+
+        let Matches {
+            trade_ids,
+            released,
+        } = state.write().resume_due_halts(Utc::now());
+
+        settle_trades(&state, &trade_ids).await;
+
+        for offer in &released {
+            release_offer(&state, offer).await;
+        }
+    }
+}
+
+async fn background_sessions(state: GState) {
+    const SECONDS: u64 = 1;
+
+    tracing::info!("Updating commodity trading sessions every {SECONDS} second(s).");
+
+    let mut interval = interval(Duration::from_secs(SECONDS));
+
+    loop {
+        interval.tick().await;
+
+        let Matches {
+            trade_ids,
+            released,
+        } = state.write().update_sessions(Utc::now());
+
+        settle_trades(&state, &trade_ids).await;
+
+        for offer in &released {
+            release_offer(&state, offer).await;
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     init_logs().await?;
@@ -190,11 +281,25 @@ async fn main() -> Result<()> {
 
     let state_arc = Arc::new(RwLock::new(state));
 
+    settle_pending_trades(&state_arc).await;
+
     let tokio_handle = Handle::current();
 
     let state_arc_clone = state_arc.clone();
     tokio_handle.spawn(background_save(state_arc_clone));
 
+    let state_arc_clone = state_arc.clone();
+    tokio_handle.spawn(background_expire(state_arc_clone));
+
+    let state_arc_clone = state_arc.clone();
+    tokio_handle.spawn(background_auction(state_arc_clone));
+
+    let state_arc_clone = state_arc.clone();
+    tokio_handle.spawn(background_resume(state_arc_clone));
+
+    let state_arc_clone = state_arc.clone();
+    tokio_handle.spawn(background_sessions(state_arc_clone));
+
     let state_arc_clone = state_arc.clone();
     ctrlc::set_handler(move || signal_handler(&state_arc_clone))?;
 
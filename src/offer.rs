@@ -1,4 +1,4 @@
-use crate::{commodity::CommodityUID, user::UserUID};
+use crate::{commodity::CommodityUID, trade::TradeUID, user::UserUID};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::fmt;
@@ -11,6 +11,162 @@ impl fmt::Display for OfferUID {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}", self.0) }
 }
 
+/// The lifecycle of an offer. Offers start `Open` (or `Dormant`, for stop
+/// offers which haven't been triggered yet) and move towards one of the
+/// terminal states (`Filled`, `Cancelled` or `Expired`), after which they never
+/// change again.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Hash, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OfferStatus {
+    Dormant,
+    #[default]
+    Open,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Expired,
+}
+
+impl OfferStatus {
+    /// Whether the offer can still trade.
+    pub(crate) fn is_live(self) -> bool {
+        matches!(self, OfferStatus::Open | OfferStatus::PartiallyFilled)
+    }
+
+    fn initial(options: &OfferOptions) -> Self {
+        if options.stop_price.is_some() {
+            OfferStatus::Dormant
+        } else {
+            OfferStatus::Open
+        }
+    }
+
+    /// Whether the offer has reached a terminal state.
+    pub(crate) fn is_final(self) -> bool {
+        matches!(
+            self,
+            OfferStatus::Filled | OfferStatus::Cancelled | OfferStatus::Expired
+        )
+    }
+
+    pub(crate) fn can_transition_to(self, next: OfferStatus) -> bool {
+        match self {
+            OfferStatus::Dormant => next != OfferStatus::Dormant,
+            OfferStatus::Open | OfferStatus::PartiallyFilled =>
+                !matches!(next, OfferStatus::Dormant | OfferStatus::Open),
+            _ => false,
+        }
+    }
+}
+
+/// How an offer is priced. Limit offers trade at `price_per_item` or better and
+/// rest in the book until filled, while market offers take whatever the book
+/// offers immediately and never rest.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Hash, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderType {
+    #[default]
+    Limit,
+    Market,
+}
+
+/// How long an offer stays in the book. Immediate-or-cancel offers cancel
+/// whatever they can't fill straight away, fill-or-kill offers are cancelled
+/// without trading unless they can be filled completely, and good-till-date
+/// offers expire at their `expires_at`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Hash, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce {
+    #[default]
+    GoodTillCancelled,
+    ImmediateOrCancel,
+    FillOrKill,
+    GoodTillDate,
+}
+
+impl TimeInForce {
+    /// Whether offers with this time in force rest in the book after matching.
+    pub(crate) fn rests(self) -> bool {
+        matches!(
+            self,
+            TimeInForce::GoodTillCancelled | TimeInForce::GoodTillDate
+        )
+    }
+}
+
+/// Options chosen when an offer is placed which control how it executes.
+///
+/// Offers with a `stop_price` stay dormant, out of the book, until the last
+/// trade price of their commodity reaches it: at or above it for bids, at or
+/// below it for asks.
+///
+/// Offers with a `display_amount` are icebergs: only that many items are shown
+/// in the book at once, and the rest are held back in reserve until the shown
+/// items have been filled.
+///
+/// `post_only` offers are rejected rather than trade as soon as they are
+/// placed, so they only ever rest in the book. `all_or_none` offers only trade
+/// if their whole remaining amount is filled at once.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OfferOptions {
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub stop_price: Option<u64>,
+    #[serde(default)]
+    pub display_amount: Option<u64>,
+    #[serde(default)]
+    pub post_only: bool,
+    #[serde(default)]
+    pub all_or_none: bool,
+}
+
+impl OfferOptions {
+    /// Checks that the options make sense for an offer placed at `now`.
+    pub(crate) fn validate(&self, now: DateTime<Utc>) -> Result<(), String> {
+        if self.stop_price == Some(0) {
+            return Err("stop_price cannot be 0".into());
+        }
+
+        if self.display_amount == Some(0) {
+            return Err("display_amount cannot be 0".into());
+        }
+
+        if self.display_amount.is_some() && self.all_or_none {
+            return Err("all_or_none offers cannot have a display_amount".into());
+        }
+
+        match (self.time_in_force, self.expires_at) {
+            (TimeInForce::GoodTillDate, None) =>
+                Err("expires_at is required for good_till_date offers".into()),
+            (TimeInForce::GoodTillDate, Some(expires_at)) if expires_at <= now =>
+                Err("expires_at must be in the future".into()),
+            (TimeInForce::GoodTillDate, Some(_)) | (_, None) => Ok(()),
+            (_, Some(_)) =>
+                Err("expires_at is only valid for good_till_date offers".into()),
+        }
+    }
+}
+
+/// A record of when an offer entered a given status.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct StatusChange {
+    pub status: OfferStatus,
+    pub datetime: DateTime<Utc>,
+}
+
+/// A single partial (or complete) fill of an offer against another offer.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct Fill {
+    pub trade_id: TradeUID,
+    pub counter_offer_id: OfferUID,
+    pub datetime: DateTime<Utc>,
+    pub item_amount: u64,
+    pub price_per_item: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Offer {
@@ -19,13 +175,498 @@ pub enum Offer {
         commodity_id: CommodityUID,
         datetime: DateTime<Utc>,
         item_amount: u64,
+        remaining_amount: u64,
         price_per_item: u64,
+        #[serde(default)]
+        fills: Vec<Fill>,
+        #[serde(default)]
+        status: OfferStatus,
+        #[serde(default)]
+        status_history: Vec<StatusChange>,
+        #[serde(default)]
+        order_type: OrderType,
+        /// What is left of the shown part of an iceberg offer.
+        #[serde(default)]
+        visible_amount: Option<u64>,
+        #[serde(flatten)]
+        options: OfferOptions,
     },
     Bid {
         user_id: UserUID,
         commodity_id: CommodityUID,
         datetime: DateTime<Utc>,
         item_amount: u64,
+        remaining_amount: u64,
         price_per_item: u64,
+        #[serde(default)]
+        fills: Vec<Fill>,
+        #[serde(default)]
+        status: OfferStatus,
+        #[serde(default)]
+        status_history: Vec<StatusChange>,
+        #[serde(default)]
+        order_type: OrderType,
+        /// What is left of the shown part of an iceberg offer.
+        #[serde(default)]
+        visible_amount: Option<u64>,
+        /// The most a market bid may spend, which is what it has escrowed.
+        #[serde(default)]
+        max_total_cost: Option<u64>,
+        #[serde(flatten)]
+        options: OfferOptions,
     },
 }
+
+impl Offer {
+    pub(crate) fn new_ask(
+        user_id: UserUID,
+        commodity_id: CommodityUID,
+        item_amount: u64,
+        price_per_item: u64,
+        order_type: OrderType,
+        options: OfferOptions,
+        datetime: DateTime<Utc>,
+    ) -> Self {
+        let status = OfferStatus::initial(&options);
+
+        Offer::Ask {
+            user_id,
+            commodity_id,
+            datetime,
+            item_amount,
+            remaining_amount: item_amount,
+            price_per_item,
+            fills: Vec::new(),
+            status,
+            status_history: vec![StatusChange { status, datetime }],
+            order_type,
+            visible_amount: options.display_amount.map(|amount| amount.min(item_amount)),
+            options,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new_bid(
+        user_id: UserUID,
+        commodity_id: CommodityUID,
+        item_amount: u64,
+        price_per_item: u64,
+        order_type: OrderType,
+        max_total_cost: Option<u64>,
+        options: OfferOptions,
+        datetime: DateTime<Utc>,
+    ) -> Self {
+        let status = OfferStatus::initial(&options);
+
+        Offer::Bid {
+            user_id,
+            commodity_id,
+            datetime,
+            item_amount,
+            remaining_amount: item_amount,
+            price_per_item,
+            fills: Vec::new(),
+            status,
+            status_history: vec![StatusChange { status, datetime }],
+            order_type,
+            visible_amount: options.display_amount.map(|amount| amount.min(item_amount)),
+            max_total_cost,
+            options,
+        }
+    }
+
+    pub(crate) fn get_user_id(&self) -> UserUID {
+        match self {
+            Offer::Ask { user_id, .. } | Offer::Bid { user_id, .. } => *user_id,
+        }
+    }
+
+    pub(crate) fn get_commodity_id(&self) -> CommodityUID {
+        match self {
+            Offer::Ask { commodity_id, .. } | Offer::Bid { commodity_id, .. } =>
+                *commodity_id,
+        }
+    }
+
+    pub(crate) fn get_datetime(&self) -> DateTime<Utc> {
+        match self {
+            Offer::Ask { datetime, .. } | Offer::Bid { datetime, .. } => *datetime,
+        }
+    }
+
+    pub(crate) fn get_remaining_amount(&self) -> u64 {
+        match self {
+            Offer::Ask {
+                remaining_amount, ..
+            }
+            | Offer::Bid {
+                remaining_amount, ..
+            } => *remaining_amount,
+        }
+    }
+
+    /// How many of the remaining items are shown in the book. This is all of
+    /// them, unless the offer is an iceberg.
+    pub(crate) fn get_visible_amount(&self) -> u64 {
+        match self {
+            Offer::Ask {
+                remaining_amount,
+                visible_amount,
+                ..
+            }
+            | Offer::Bid {
+                remaining_amount,
+                visible_amount,
+                ..
+            } => visible_amount
+                .map_or(*remaining_amount, |visible| visible.min(*remaining_amount)),
+        }
+    }
+
+    /// The offer as shown to everyone other than its owner, with the reserve
+    /// of an iceberg offer left out of its amounts.
+    pub(crate) fn as_public(&self) -> Offer {
+        let hidden = self.get_remaining_amount() - self.get_visible_amount();
+        let mut offer = self.clone();
+
+        match &mut offer {
+            Offer::Ask {
+                item_amount,
+                remaining_amount,
+                ..
+            }
+            | Offer::Bid {
+                item_amount,
+                remaining_amount,
+                ..
+            } => {
+                *item_amount -= hidden;
+                *remaining_amount -= hidden;
+            },
+        }
+
+        offer
+    }
+
+    pub(crate) fn get_price_per_item(&self) -> u64 {
+        match self {
+            Offer::Ask { price_per_item, .. } | Offer::Bid { price_per_item, .. } =>
+                *price_per_item,
+        }
+    }
+
+    /// The CSH held in the market account for what is left of a bid. For
+    /// market bids this is whatever is left of their budget. Asks never hold
+    /// any funds.
+    pub(crate) fn get_escrowed_funds(&self) -> u64 {
+        match self {
+            Offer::Ask { .. } => 0,
+            Offer::Bid {
+                order_type: OrderType::Market,
+                max_total_cost,
+                fills,
+                ..
+            } => fills
+                .iter()
+                .fold(max_total_cost.unwrap_or(0), |budget, fill| {
+                    budget.saturating_sub(
+                        fill.item_amount.saturating_mul(fill.price_per_item),
+                    )
+                }),
+            Offer::Bid {
+                remaining_amount,
+                price_per_item,
+                ..
+            } => remaining_amount.saturating_mul(*price_per_item),
+        }
+    }
+
+    /// The items locked in the seller's holdings for what is left of an ask.
+    /// Bids never lock any items.
+    pub(crate) fn get_reserved_items(&self) -> u64 {
+        match self {
+            Offer::Ask {
+                remaining_amount, ..
+            } => *remaining_amount,
+            Offer::Bid { .. } => 0,
+        }
+    }
+
+    pub(crate) fn get_status(&self) -> OfferStatus {
+        match self {
+            Offer::Ask { status, .. } | Offer::Bid { status, .. } => *status,
+        }
+    }
+
+    /// Moves the offer into `next`, recording the time of the change. Returns
+    /// `false` and leaves the offer untouched if the transition isn't allowed.
+    pub(crate) fn transition(
+        &mut self,
+        next: OfferStatus,
+        datetime: DateTime<Utc>,
+    ) -> bool {
+        match self {
+            Offer::Ask {
+                status,
+                status_history,
+                ..
+            }
+            | Offer::Bid {
+                status,
+                status_history,
+                ..
+            } => {
+                if !status.can_transition_to(next) {
+                    return false;
+                }
+
+                // Repeated partial fills only record the first time the offer
+                // became partially filled.
+                if *status != next {
+                    status_history.push(StatusChange {
+                        status: next,
+                        datetime,
+                    });
+                }
+
+                *status = next;
+
+                true
+            },
+        }
+    }
+
+    pub(crate) fn get_order_type(&self) -> OrderType {
+        match self {
+            Offer::Ask { order_type, .. } | Offer::Bid { order_type, .. } => *order_type,
+        }
+    }
+
+    pub(crate) fn get_options(&self) -> OfferOptions {
+        match self {
+            Offer::Ask { options, .. } | Offer::Bid { options, .. } => *options,
+        }
+    }
+
+    /// Whether the offer is left resting in the book once it has been matched.
+    pub(crate) fn rests(&self) -> bool {
+        self.get_order_type() == OrderType::Limit
+            && self.get_options().time_in_force.rests()
+    }
+
+    /// Whether a dormant stop offer should be activated now that its
+    /// commodity last traded at `last_price`.
+    pub(crate) fn is_triggered_by(&self, last_price: u64) -> bool {
+        let Some(stop_price) = self.get_options().stop_price else {
+            return false;
+        };
+
+        if self.is_ask() {
+            last_price <= stop_price
+        } else {
+            last_price >= stop_price
+        }
+    }
+
+    /// Whether a good-till-date offer has passed its expiry at `now`.
+    pub(crate) fn has_expired(&self, now: DateTime<Utc>) -> bool {
+        self.get_options()
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub(crate) fn is_ask(&self) -> bool { matches!(self, Offer::Ask { .. }) }
+
+    /// Undoes the fill made by `trade_id`, returning its items to the remaining
+    /// amount. Offers which have since been cancelled or expired stay that way,
+    /// and offers which never rest in the book (such as market offers) are
+    /// cancelled rather than reopened.
+    pub(crate) fn revert_fill(&mut self, trade_id: TradeUID, datetime: DateTime<Utc>) {
+        let rests = self.rests();
+        let display_amount = self.get_options().display_amount;
+
+        match self {
+            Offer::Ask {
+                remaining_amount,
+                visible_amount,
+                fills,
+                status,
+                status_history,
+                ..
+            }
+            | Offer::Bid {
+                remaining_amount,
+                visible_amount,
+                fills,
+                status,
+                status_history,
+                ..
+            } => {
+                let Some(index) = fills.iter().position(|fill| fill.trade_id == trade_id) else {
+                    return;
+                };
+
+                let fill = fills.remove(index);
+                *remaining_amount += fill.item_amount;
+
+                if let Some((visible, display_amount)) =
+                    visible_amount.as_mut().zip(display_amount)
+                {
+                    *visible = (*visible + fill.item_amount).min(display_amount);
+                }
+
+                if !matches!(status, OfferStatus::PartiallyFilled | OfferStatus::Filled) {
+                    return;
+                }
+
+                let restored = if !rests {
+                    OfferStatus::Cancelled
+                } else if fills.is_empty() {
+                    OfferStatus::Open
+                } else {
+                    OfferStatus::PartiallyFilled
+                };
+
+                if *status != restored {
+                    *status = restored;
+                    status_history.push(StatusChange {
+                        status: restored,
+                        datetime,
+                    });
+                }
+            },
+        }
+    }
+
+    /// Reduces the amount of the offer still open to `remaining`. The offer
+    /// keeps its place in the queue.
+    pub(crate) fn reduce_remaining(&mut self, remaining: u64) {
+        match self {
+            Offer::Ask {
+                item_amount,
+                remaining_amount,
+                visible_amount,
+                ..
+            }
+            | Offer::Bid {
+                item_amount,
+                remaining_amount,
+                visible_amount,
+                ..
+            } => {
+                let reduced_by = remaining_amount.saturating_sub(remaining);
+
+                *item_amount = item_amount.saturating_sub(reduced_by);
+                *remaining_amount -= reduced_by;
+
+                if let Some(visible) = visible_amount {
+                    *visible = (*visible).min(*remaining_amount);
+                }
+            },
+        }
+    }
+
+    /// Changes the price of the offer. Repricing moves the offer to the back of
+    /// the queue, so `datetime` is reset to `now`.
+    pub(crate) fn reprice(&mut self, price: u64, now: DateTime<Utc>) {
+        match self {
+            Offer::Ask {
+                price_per_item,
+                datetime,
+                ..
+            }
+            | Offer::Bid {
+                price_per_item,
+                datetime,
+                ..
+            } => {
+                *price_per_item = price;
+                *datetime = now;
+            },
+        }
+    }
+
+    /// Records a fill of `amount` items at `price_per_item` against
+    /// `counter_offer_id`, removing them from the remaining amount.
+    pub(crate) fn fill(
+        &mut self,
+        trade_id: TradeUID,
+        counter_offer_id: OfferUID,
+        amount: u64,
+        price_per_item: u64,
+        datetime: DateTime<Utc>,
+    ) {
+        match self {
+            Offer::Ask {
+                remaining_amount,
+                visible_amount,
+                fills,
+                ..
+            }
+            | Offer::Bid {
+                remaining_amount,
+                visible_amount,
+                fills,
+                ..
+            } => {
+                *remaining_amount = remaining_amount.saturating_sub(amount);
+
+                if let Some(visible) = visible_amount {
+                    *visible = visible.saturating_sub(amount);
+                }
+
+                fills.push(Fill {
+                    trade_id,
+                    counter_offer_id,
+                    datetime,
+                    item_amount: amount,
+                    price_per_item,
+                });
+            },
+        }
+
+        let next = if self.get_remaining_amount() == 0 {
+            OfferStatus::Filled
+        } else {
+            OfferStatus::PartiallyFilled
+        };
+
+        self.transition(next, datetime);
+    }
+
+    /// Shows the next part of an iceberg offer's reserve once everything it was
+    /// showing has been filled. Replenishing moves the offer to the back of the
+    /// queue, so `datetime` is reset to `now`. Returns whether the offer was
+    /// replenished.
+    pub(crate) fn replenish(&mut self, now: DateTime<Utc>) -> bool {
+        let display_amount = self.get_options().display_amount;
+
+        match self {
+            Offer::Ask {
+                remaining_amount,
+                visible_amount,
+                datetime,
+                ..
+            }
+            | Offer::Bid {
+                remaining_amount,
+                visible_amount,
+                datetime,
+                ..
+            } => {
+                let Some((visible, display_amount)) = visible_amount.as_mut().zip(display_amount) else {
+                    return false;
+                };
+
+                if *visible > 0 || *remaining_amount == 0 {
+                    return false;
+                }
+
+                *visible = display_amount.min(*remaining_amount);
+                *datetime = now;
+
+                true
+            },
+        }
+    }
+}
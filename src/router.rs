@@ -1,15 +1,20 @@
 use crate::{
+    error::{ApiError, ErrorCode},
     routes::{
-        create_ask, create_bid, get_asks, get_asks_for_user, get_bids, get_bids_for_user,
-        get_commodity_from_id, get_offers, get_offers_for_user, get_user_from_id,
-        get_users, properties,
+        amend_offer, cancel_offer, cancel_offers, create_ask, create_bid,
+        create_market_ask, create_market_bid, get_asks, get_asks_for_user, get_bids,
+        get_bids_for_user, get_book, get_commodity_from_id, get_holdings_for_user,
+        get_offers, get_offers_for_user, get_ticker_for_commodity, get_tickers,
+        get_trades, get_trades_for_commodity, get_trades_for_user, get_user_from_id,
+        get_users, issue_commodity, properties, resume_trading, set_circuit_breaker,
+        set_schedule, set_trading_mode, set_trading_rules,
     },
     state::GState,
 };
 use axum::{
-    // error_handling::HandleErrorLayer,
+    error_handling::HandleErrorLayer,
     extract::State,
-    http::{self, Method, Request, StatusCode, Uri},
+    http::{self, Method, Request, Uri},
     middleware::{self, Next},
     response::{Redirect, Response},
     routing::{get, post, IntoMakeService},
@@ -18,7 +23,7 @@ use axum::{
 use ccash_rs::{methods as m, CCashSession, CCashUser};
 use parking_lot::RwLock;
 use std::sync::Arc;
-// use tower::ServiceBuilder;
+use tower::ServiceBuilder;
 use tower_http::trace::TraceLayer;
 
 pub(crate) struct Router {
@@ -48,37 +53,36 @@ impl Router {
         )>,
         mut req: Request<B>,
         next: Next<B>,
-    ) -> Result<Response, StatusCode> {
+    ) -> Result<Response, ApiError> {
+        let unauthorized =
+            |message: &str| ApiError::new(ErrorCode::Unauthorized, message);
+
         let auth_header = req
             .headers()
             .get(http::header::AUTHORIZATION)
             .and_then(|header| header.to_str().ok());
 
         let Some(auth_header) = auth_header else {
-            return Err(StatusCode::UNAUTHORIZED);
+            return Err(unauthorized("Missing Authorization header"));
         };
 
-        if !auth_header.starts_with("Basic ") {
-            return Err(StatusCode::EXPECTATION_FAILED);
-        }
-
-        let auth_header = auth_header.replace("Basic ", "");
-
-        let Ok(decoded) = base64::decode(auth_header) else {
-            return Err(StatusCode::EXPECTATION_FAILED);
+        let Some(auth_header) = auth_header.strip_prefix("Basic ") else {
+            return Err(unauthorized("Authorization header must use Basic auth"));
         };
 
-        let Ok(decoded) =  String::from_utf8(decoded) else {
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        };
+        let credentials = base64::decode(auth_header)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok());
 
-        let splits = decoded.split(':').collect::<Vec<&str>>();
-        let Some((username, password)) = splits.first().zip(splits.last()) else {
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        let Some((username, password)) = credentials
+            .as_deref()
+            .and_then(|credentials| credentials.split_once(':'))
+        else {
+            return Err(unauthorized("Malformed Basic auth credentials"));
         };
 
         let Ok(user) = CCashUser::new(username, password) else {
-            return Err(StatusCode::UNAUTHORIZED);
+            return Err(unauthorized("Invalid username or password"));
         };
 
         if let Some(ccash_uri) = ccash_uri {
@@ -86,7 +90,10 @@ impl Router {
                 let mut session = CCashSession::new(&ccash_uri);
 
                 if session.establish_connection().await.is_err() {
-                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                    return Err(ApiError::new(
+                        ErrorCode::LedgerUnavailable,
+                        "Could not connect to the CCash ledger",
+                    ));
                 }
 
                 *ccash_session.write() = Some(session.clone());
@@ -96,16 +103,19 @@ impl Router {
 
             if let Ok(contains) = m::contains_user(&session, &user).await {
                 if !contains {
-                    return Err(StatusCode::NOT_FOUND);
+                    return Err(unauthorized("Invalid username or password"));
                 }
             }
 
             if let Ok(correct) = m::verify_password(&session, &user).await {
                 if !correct {
-                    return Err(StatusCode::NOT_FOUND);
+                    return Err(unauthorized("Invalid username or password"));
                 }
             } else {
-                return Err(StatusCode::NOT_FOUND);
+                return Err(ApiError::new(
+                    ErrorCode::LedgerUnavailable,
+                    "Could not verify credentials with the CCash ledger",
+                ));
             }
         }
 
@@ -113,15 +123,10 @@ impl Router {
         Ok(next.run(req).await)
     }
 
-    #[allow(dead_code)]
-    async fn error_handler(
-        _method: Method,
-        _uri: Uri,
-        error: BoxError,
-    ) -> (StatusCode, String) {
-        dbg!(&error);
+    async fn error_handler(method: Method, uri: Uri, error: BoxError) -> ApiError {
+        tracing::error!("Unhandled error for {method} {uri}: {error}");
 
-        (StatusCode::NOT_ACCEPTABLE, format!("{error}"))
+        ApiError::new(ErrorCode::Internal, "Internal server error")
     }
 
     fn v1_routes(&self) -> axum::Router<GState> {
@@ -133,12 +138,33 @@ impl Router {
             .route("/get/bids/:username", get(get_bids_for_user))
             .route("/get/offers", get(get_offers))
             .route("/get/offers/:username", get(get_offers_for_user))
+            .route("/get/trades", get(get_trades))
+            .route("/get/trades/:username", get(get_trades_for_user))
+            .route("/get/trades/commodity/:id", get(get_trades_for_commodity))
             .route("/get/user/:id", get(get_user_from_id))
             .route("/get/commodity/:id", get(get_commodity_from_id))
+            .route("/get/holdings/:username", get(get_holdings_for_user))
+            .route("/get/book/:commodity", get(get_book))
+            .route("/get/tickers", get(get_tickers))
+            .route("/get/ticker/:commodity", get(get_ticker_for_commodity))
             .merge(
                 axum::Router::new()
                     .route("/create/ask", post(create_ask))
                     .route("/create/bid", post(create_bid))
+                    .route("/create/market/ask", post(create_market_ask))
+                    .route("/create/market/bid", post(create_market_bid))
+                    .route("/cancel/offer/:id", post(cancel_offer))
+                    .route("/amend/offer/:id", post(amend_offer))
+                    .route("/cancel/offers", post(cancel_offers))
+                    .route("/issue/commodity/:commodity", post(issue_commodity))
+                    .route("/set/commodity/:commodity/mode", post(set_trading_mode))
+                    .route("/set/commodity/:commodity/rules", post(set_trading_rules))
+                    .route(
+                        "/set/commodity/:commodity/breaker",
+                        post(set_circuit_breaker),
+                    )
+                    .route("/set/commodity/:commodity/schedule", post(set_schedule))
+                    .route("/resume/commodity/:commodity", post(resume_trading))
                     .route_layer(middleware::from_fn_with_state(
                         (self.ccash_session.clone(), self.ccash_uri.clone()),
                         Self::auth,
@@ -161,8 +187,11 @@ impl Router {
             .with_state(Arc::clone(&self.state))
     }
 
-    async fn not_found() -> &'static str {
-        "Route not found. Please use \"/help\" for help with routes."
+    async fn not_found() -> ApiError {
+        ApiError::new(
+            ErrorCode::RouteNotFound,
+            "Route not found. Please use \"/help\" for help with routes.",
+        )
     }
 
     pub(crate) fn build(self) -> IntoMakeService<axum::Router> {
@@ -172,10 +201,11 @@ impl Router {
             .nest("/api", api_routes)
             .route("/", get(|| async { Redirect::permanent("/api/help") }))
             .layer(TraceLayer::new_for_http())
-            // .layer(
-            //     ServiceBuilder::new()
-            //         .layer(HandleErrorLayer::new(Self::error_handler).into()),
-            // )
+            .layer(
+                ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(Self::error_handler))
+                    .map_err(BoxError::from),
+            )
             .fallback(Self::not_found)
             .with_state(self.state)
             .into_make_service()
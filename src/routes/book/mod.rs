@@ -0,0 +1,54 @@
+use crate::{
+    book::PriceLevel,
+    commodity::CommodityUID,
+    error::ApiError,
+    extract::{Json, Path, Query},
+    state::GState,
+};
+use axum::extract::State;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_BOOK_DEPTH: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BookQueryParams {
+    pub depth: Option<usize>,
+}
+
+/// A commodity's order book, aggregated by price level with the best prices
+/// first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BookResponse {
+    pub commodity_id: CommodityUID,
+    pub commodity_name: String,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+pub async fn get_book(
+    Query(params): Query<BookQueryParams>,
+    State(state): State<GState>,
+    Path(commodity_name): Path<String>,
+) -> Result<Json<BookResponse>, ApiError> {
+    let BookQueryParams { depth } = params;
+    let mut depth = depth.unwrap_or(10);
+
+    if depth == 0 || depth > MAX_BOOK_DEPTH {
+        depth = MAX_BOOK_DEPTH;
+    }
+
+    let state = state.read();
+
+    let Some(commodity_id) = state.get_commodity_id(&commodity_name) else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    let (bids, asks) = state.get_book_depth(commodity_id, depth);
+
+    Ok(Json(BookResponse {
+        commodity_id,
+        commodity_name,
+        bids,
+        asks,
+    }))
+}
@@ -0,0 +1,279 @@
+use crate::{
+    commodity::{CircuitBreaker, Commodity, TradingMode, TradingRules, TradingSchedule},
+    error::ApiError,
+    extract::{Json, Path},
+    state::{release_offer, settle_trades, GState, Matches},
+    trade::Trade,
+};
+use axum::{extract::State, Extension};
+use ccash_rs::CCashUser;
+use serde::{Deserialize, Serialize};
+
+/// New items to issue to a user.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IssueCommodity {
+    pub username: String,
+    pub item_amount: u64,
+}
+
+/// A commodity after an update, along with any trades the update caused.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommodityUpdateResponse {
+    pub commodity: Commodity,
+    pub trades: Vec<Trade>,
+}
+
+/// Switches a commodity between continuous trading and periodic auctions. Only
+/// the market user can change how a commodity is traded.
+pub async fn set_trading_mode(
+    Extension(ccash_user): Extension<CCashUser>,
+    State(state): State<GState>,
+    Path(commodity_name): Path<String>,
+    Json(trading_mode): Json<TradingMode>,
+) -> Result<Json<CommodityUpdateResponse>, ApiError> {
+    if !state.read().is_market_user(&ccash_user) {
+        return Err(ApiError::forbidden(
+            "Only the market user can change a commodity's trading mode",
+        ));
+    }
+
+    let Some(commodity_id) = state.read().get_commodity_id(&commodity_name) else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    let result = state.write().set_trading_mode(commodity_id, trading_mode);
+
+    let Matches {
+        trade_ids,
+        released,
+    } = match result {
+        Ok(matches) => matches,
+        Err(e) => return Err(ApiError::from(e)),
+    };
+
+    settle_trades(&state, &trade_ids).await;
+
+    for offer in &released {
+        release_offer(&state, offer).await;
+    }
+
+    let state = state.read();
+    let commodity = state
+        .get_commodities()
+        .get(&commodity_id)
+        .map(|kv| kv.value().read().clone());
+
+    let Some(commodity) = commodity else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    Ok(Json(CommodityUpdateResponse {
+        commodity,
+        trades: state.get_trades_from_ids(&trade_ids),
+    }))
+}
+
+/// Issues new items of a commodity to a user, creating the commodity if it
+/// doesn't exist yet. Only the market user can issue items.
+pub async fn issue_commodity(
+    Extension(ccash_user): Extension<CCashUser>,
+    State(state): State<GState>,
+    Path(commodity_name): Path<String>,
+    Json(IssueCommodity {
+        username,
+        item_amount,
+    }): Json<IssueCommodity>,
+) -> Result<Json<Commodity>, ApiError> {
+    let mut state = state.write();
+
+    if !state.is_market_user(&ccash_user) {
+        return Err(ApiError::forbidden(
+            "Only the market user can issue commodity items",
+        ));
+    }
+
+    let user_id = state.get_or_add_username(&username);
+
+    let commodity_id = match state.issue_commodity(&commodity_name, user_id, item_amount)
+    {
+        Ok(commodity_id) => commodity_id,
+        Err(e) => return Err(ApiError::from(e)),
+    };
+
+    let commodity = state
+        .get_commodities()
+        .get(&commodity_id)
+        .map(|kv| kv.value().read().clone());
+
+    commodity
+        .map(Json)
+        .ok_or_else(|| ApiError::commodity_not_found(commodity_name))
+}
+
+/// Replaces a commodity's tick size, lot size and order value limits. Only the
+/// market user can change a commodity's trading rules.
+pub async fn set_trading_rules(
+    Extension(ccash_user): Extension<CCashUser>,
+    State(state): State<GState>,
+    Path(commodity_name): Path<String>,
+    Json(trading_rules): Json<TradingRules>,
+) -> Result<Json<Commodity>, ApiError> {
+    let mut state = state.write();
+
+    if !state.is_market_user(&ccash_user) {
+        return Err(ApiError::forbidden(
+            "Only the market user can change a commodity's trading rules",
+        ));
+    }
+
+    let Some(commodity_id) = state.get_commodity_id(&commodity_name) else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    if let Err(e) = state.set_trading_rules(commodity_id, trading_rules) {
+        return Err(ApiError::from(e));
+    }
+
+    let commodity = state
+        .get_commodities()
+        .get(&commodity_id)
+        .map(|kv| kv.value().read().clone());
+
+    commodity
+        .map(Json)
+        .ok_or_else(|| ApiError::commodity_not_found(commodity_name))
+}
+
+/// Replaces a commodity's price band and halt settings. Only the market user
+/// can change a commodity's circuit breaker.
+pub async fn set_circuit_breaker(
+    Extension(ccash_user): Extension<CCashUser>,
+    State(state): State<GState>,
+    Path(commodity_name): Path<String>,
+    Json(circuit_breaker): Json<CircuitBreaker>,
+) -> Result<Json<Commodity>, ApiError> {
+    let mut state = state.write();
+
+    if !state.is_market_user(&ccash_user) {
+        return Err(ApiError::forbidden(
+            "Only the market user can change a commodity's circuit breaker",
+        ));
+    }
+
+    let Some(commodity_id) = state.get_commodity_id(&commodity_name) else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    if let Err(e) = state.set_circuit_breaker(commodity_id, circuit_breaker) {
+        return Err(ApiError::from(e));
+    }
+
+    let commodity = state
+        .get_commodities()
+        .get(&commodity_id)
+        .map(|kv| kv.value().read().clone());
+
+    commodity
+        .map(Json)
+        .ok_or_else(|| ApiError::commodity_not_found(commodity_name))
+}
+
+/// Lifts a halt on trading in a commodity before it would end by itself. Only
+/// the market user can resume trading.
+pub async fn resume_trading(
+    Extension(ccash_user): Extension<CCashUser>,
+    State(state): State<GState>,
+    Path(commodity_name): Path<String>,
+) -> Result<Json<CommodityUpdateResponse>, ApiError> {
+    if !state.read().is_market_user(&ccash_user) {
+        return Err(ApiError::forbidden(
+            "Only the market user can resume trading in a commodity",
+        ));
+    }
+
+    let Some(commodity_id) = state.read().get_commodity_id(&commodity_name) else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    let result = state.write().resume_trading(commodity_id);
+
+    let Matches {
+        trade_ids,
+        released,
+    } = match result {
+        Ok(matches) => matches,
+        Err(e) => return Err(ApiError::from(e)),
+    };
+
+    settle_trades(&state, &trade_ids).await;
+
+    for offer in &released {
+        release_offer(&state, offer).await;
+    }
+
+    let state = state.read();
+    let commodity = state
+        .get_commodities()
+        .get(&commodity_id)
+        .map(|kv| kv.value().read().clone());
+
+    let Some(commodity) = commodity else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    Ok(Json(CommodityUpdateResponse {
+        commodity,
+        trades: state.get_trades_from_ids(&trade_ids),
+    }))
+}
+
+/// Sets a commodity's trading hours, or removes them if the body is `null` so
+/// that it is always open. Only the market user can change a commodity's
+/// trading hours.
+pub async fn set_schedule(
+    Extension(ccash_user): Extension<CCashUser>,
+    State(state): State<GState>,
+    Path(commodity_name): Path<String>,
+    Json(schedule): Json<Option<TradingSchedule>>,
+) -> Result<Json<CommodityUpdateResponse>, ApiError> {
+    if !state.read().is_market_user(&ccash_user) {
+        return Err(ApiError::forbidden(
+            "Only the market user can change a commodity's trading hours",
+        ));
+    }
+
+    let Some(commodity_id) = state.read().get_commodity_id(&commodity_name) else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    let result = state.write().set_schedule(commodity_id, schedule);
+
+    let Matches {
+        trade_ids,
+        released,
+    } = match result {
+        Ok(matches) => matches,
+        Err(e) => return Err(ApiError::from(e)),
+    };
+
+    settle_trades(&state, &trade_ids).await;
+
+    for offer in &released {
+        release_offer(&state, offer).await;
+    }
+
+    let state = state.read();
+    let commodity = state
+        .get_commodities()
+        .get(&commodity_id)
+        .map(|kv| kv.value().read().clone());
+
+    let Some(commodity) = commodity else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    Ok(Json(CommodityUpdateResponse {
+        commodity,
+        trades: state.get_trades_from_ids(&trade_ids),
+    }))
+}
@@ -1,9 +1,20 @@
+mod book;
+mod commodity;
 mod offer;
+mod ticker;
+mod trade;
 mod util;
 
-use crate::state::{AppProperties, GState, Users};
-use axum::{extract::State, Json};
+use crate::{
+    extract::Json,
+    state::{AppProperties, GState, Users},
+};
+use axum::extract::State;
+pub use book::*;
+pub use commodity::*;
 pub use offer::*;
+pub use ticker::*;
+pub use trade::*;
 pub use util::*;
 
 pub async fn properties(State(state): State<GState>) -> Json<AppProperties> {
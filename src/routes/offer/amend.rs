@@ -0,0 +1,105 @@
+use super::check_offer_owner;
+use crate::{
+    error::ApiError,
+    extract::{Json, Path},
+    offer::{Offer, OfferUID},
+    state::{
+        escrow_funds, refund_escrow, release_offer, settle_trades, Amendment, GState,
+    },
+    trade::Trade,
+};
+use axum::{extract::State, Extension};
+use ccash_rs::CCashUser;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Changes to make to an open offer. Reducing `remaining_amount` keeps the
+/// offer's time priority, changing `price_per_item` loses it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AmendOffer {
+    pub price_per_item: Option<u64>,
+    pub remaining_amount: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AmendResponse {
+    pub offer: Offer,
+    pub trades: Vec<Trade>,
+}
+
+pub async fn amend_offer(
+    Extension(ccash_user): Extension<CCashUser>,
+    State(state): State<GState>,
+    Path(id): Path<Uuid>,
+    Json(AmendOffer {
+        price_per_item,
+        remaining_amount,
+    }): Json<AmendOffer>,
+) -> Result<Json<AmendResponse>, ApiError> {
+    if price_per_item.is_none() && remaining_amount.is_none() {
+        return Err(ApiError::invalid_request(
+            "price_per_item and/or remaining_amount must be provided",
+        ));
+    }
+
+    let user_id = state.write().get_or_add_user(&ccash_user);
+
+    let offer_id = OfferUID(id);
+
+    check_offer_owner(&state, offer_id, user_id)?;
+
+    // Raising the price of a bid needs more funds escrowed, which have to be
+    // collected before the amendment is made. Whatever isn't needed afterwards
+    // is refunded.
+    let collected =
+        state
+            .read()
+            .get_escrow_increase(offer_id, price_per_item, remaining_amount);
+
+    if let Err(e) = escrow_funds(&state, &ccash_user, collected).await {
+        return Err(ApiError::escrow_failed(collected, e));
+    }
+
+    let result =
+        state
+            .write()
+            .amend_offer(offer_id, user_id, price_per_item, remaining_amount);
+
+    match result {
+        Ok(Amendment {
+            trade_ids,
+            released,
+            escrow_before,
+            escrow_after,
+        }) => {
+            let refund = (escrow_before + collected).saturating_sub(escrow_after);
+            refund_escrow(&state, user_id, refund).await;
+
+            settle_trades(&state, &trade_ids).await;
+
+            for offer in &released {
+                release_offer(&state, offer).await;
+            }
+
+            let state = state.read();
+            let offer = state
+                .get_offers()
+                .get(&offer_id)
+                .map(|kv| kv.value().read().clone());
+
+            let Some(offer) = offer else {
+                return Err(ApiError::offer_not_found(offer_id));
+            };
+
+            Ok(Json(AmendResponse {
+                offer,
+                trades: state.get_trades_from_ids(&trade_ids),
+            }))
+        },
+        Err(e) => {
+            refund_escrow(&state, user_id, collected).await;
+
+            Err(ApiError::from(e))
+        },
+    }
+}
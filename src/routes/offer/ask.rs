@@ -1,21 +1,25 @@
 use super::{
-    offer_sort, OfferQueryParams, OfferResponse, OfferSortBy, MAX_OFFER_RESPONSE,
+    offer_sort, offer_status_filter, OfferQueryParams, OfferResponse, OfferSize,
+    OfferSortBy, MAX_OFFER_RESPONSE,
 };
-use crate::{offer::Offer, state::GState};
-use axum::{
-    extract::{Path, Query, State},
-    response::Result,
-    Extension, Json,
+use crate::{
+    error::ApiError,
+    extract::{Json, Path, Query},
+    offer::{Offer, OfferOptions, OrderType},
+    state::{release_offer, settle_trades, GState, Matches},
 };
+use axum::{extract::State, Extension};
 use ccash_rs::CCashUser;
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateAsk {
     pub commodity_name: String,
-    pub total_cost: u64,
-    pub cost_per_item: u64,
+    #[serde(flatten)]
+    pub size: OfferSize,
+    #[serde(flatten)]
+    pub options: OfferOptions,
 }
 
 pub async fn create_ask(
@@ -23,46 +27,83 @@ pub async fn create_ask(
     State(state): State<GState>,
     Json(CreateAsk {
         commodity_name,
-        total_cost,
-        cost_per_item,
+        size,
+        options,
     }): Json<CreateAsk>,
-) -> Result<Json<OfferResponse>, Json<Value>> {
-    let Some(total) = total_cost.checked_div(cost_per_item) else {
-        return Err(Json(json!("cost_per_item or total_cost cannot be 0")));
+) -> Result<Json<OfferResponse>, ApiError> {
+    let (item_amount, price_per_item) = size.resolve()?;
+
+    if let Err(e) = options.validate(Utc::now()) {
+        return Err(ApiError::invalid_request(e));
+    }
+
+    let Some(commodity_id) = state.read().get_commodity_id(&commodity_name) else {
+        return Err(ApiError::commodity_not_found(commodity_name));
     };
 
     let user_id = state.write().get_or_add_user(&ccash_user);
-    let commodity_id =
-        state
-            .write()
-            .get_or_add_commodity(&commodity_name, total, user_id);
 
-    let ask_id = state
-        .write()
-        .add_ask(commodity_id, user_id, total, cost_per_item);
+    let result = state.write().add_ask(
+        commodity_id,
+        user_id,
+        item_amount,
+        price_per_item,
+        OrderType::Limit,
+        options,
+    );
+
+    let (
+        ask_id,
+        Matches {
+            trade_ids,
+            released,
+        },
+    ) = match result {
+        Ok(ids) => ids,
+        Err(e) => return Err(ApiError::from(e)),
+    };
 
     if let Some(user) = state.write().get_users_mut().get_mut(&user_id) {
         user.write().add_offer_id(ask_id);
     }
 
-    if let Some(commodity) = state.write().get_commodities_mut().get_mut(&commodity_id) {
-        commodity.write().add_owner_id(user_id);
+    settle_trades(&state, &trade_ids).await;
+
+    for offer in &released {
+        release_offer(&state, offer).await;
     }
 
+    let offer = state
+        .read()
+        .get_offers()
+        .get(&ask_id)
+        .map(|kv| kv.value().read().clone());
+
+    let Some(offer) = offer else {
+        return Err(ApiError::offer_not_found(ask_id));
+    };
+
     Ok(Json(OfferResponse {
         message: format!(
-            "Ask for {total} \"{commodity_name}\" item(s) at {cost_per_item} CSH each \
-             by {}",
-            ccash_user.get_username()
+            "Ask for {item_amount} \"{commodity_name}\" item(s) at {price_per_item} CSH \
+             each by {}, matched against {} bid(s)",
+            ccash_user.get_username(),
+            trade_ids.len()
         ),
+        offer_id: ask_id,
+        offer,
     }))
 }
 
 pub async fn get_asks(
-    params: Option<Query<OfferQueryParams>>,
+    Query(params): Query<OfferQueryParams>,
     State(state): State<GState>,
-) -> Result<Json<Vec<Offer>>, Json<Value>> {
-    let Query(OfferQueryParams { limit, sort_by }) = params.unwrap_or_default();
+) -> Result<Json<Vec<Offer>>, ApiError> {
+    let OfferQueryParams {
+        limit,
+        status,
+        sort_by,
+    } = params;
     let mut limit = limit.unwrap_or(100);
     let sort_by = sort_by.unwrap_or(OfferSortBy::DateDescending);
 
@@ -81,7 +122,8 @@ pub async fn get_asks(
 
             matches!(v.clone(), Offer::Ask { .. })
         })
-        .map(|kv| kv.value().read().clone())
+        .map(|kv| kv.value().read().as_public())
+        .filter(|offer| offer_status_filter(status, offer))
         .collect::<Vec<_>>();
 
     asks.sort_by(|a, b| offer_sort(sort_by.clone(), a, b));
@@ -92,11 +134,15 @@ pub async fn get_asks(
 }
 
 pub async fn get_asks_for_user(
-    params: Option<Query<OfferQueryParams>>,
+    Query(params): Query<OfferQueryParams>,
     State(state): State<GState>,
     Path(username): Path<String>,
-) -> Result<Json<Vec<Offer>>, Json<Value>> {
-    let Query(OfferQueryParams { limit, sort_by }) = params.unwrap_or_default();
+) -> Result<Json<Vec<Offer>>, ApiError> {
+    let OfferQueryParams {
+        limit,
+        status,
+        sort_by,
+    } = params;
     let mut limit = limit.unwrap_or(100);
     let sort_by = sort_by.unwrap_or(OfferSortBy::DateDescending);
 
@@ -129,7 +175,8 @@ pub async fn get_asks_for_user(
                 false
             })
             .filter_map(|ask_id| state.get_offers().get(ask_id))
-            .map(|kv| kv.value().read().clone())
+            .map(|kv| kv.value().read().as_public())
+            .filter(|offer| offer_status_filter(status, offer))
             .collect::<Vec<_>>();
 
         asks.sort_by(|a, b| offer_sort(sort_by.clone(), a, b));
@@ -139,5 +186,5 @@ pub async fn get_asks_for_user(
         return Ok(Json(asks));
     }
 
-    Err(Json(json!({}))) // TODO
+    Err(ApiError::user_not_found(username))
 }
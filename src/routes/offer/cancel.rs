@@ -0,0 +1,65 @@
+use super::check_offer_owner;
+use crate::{
+    commodity::CommodityUID,
+    error::ApiError,
+    extract::{Json, Path, Query},
+    offer::{Offer, OfferUID},
+    state::{release_offer, GState},
+};
+use axum::{extract::State, Extension};
+use ccash_rs::CCashUser;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CancelQueryParams {
+    pub commodity_id: Option<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CancelResponse {
+    pub cancelled: Vec<OfferUID>,
+}
+
+pub async fn cancel_offer(
+    Extension(ccash_user): Extension<CCashUser>,
+    State(state): State<GState>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Offer>, ApiError> {
+    let user_id = state.write().get_or_add_user(&ccash_user);
+    let offer_id = OfferUID(id);
+
+    check_offer_owner(&state, offer_id, user_id)?;
+
+    let result = state.write().cancel_offer(offer_id, user_id);
+
+    match result {
+        Ok(offer) => {
+            release_offer(&state, &offer).await;
+
+            Ok(Json(offer))
+        },
+        Err(e) => Err(ApiError::from(e)),
+    }
+}
+
+pub async fn cancel_offers(
+    Extension(ccash_user): Extension<CCashUser>,
+    Query(params): Query<CancelQueryParams>,
+    State(state): State<GState>,
+) -> Json<CancelResponse> {
+    let CancelQueryParams { commodity_id } = params;
+
+    let user_id = state.write().get_or_add_user(&ccash_user);
+    let cancelled = state
+        .write()
+        .cancel_offers_for_user(user_id, commodity_id.map(CommodityUID));
+
+    for (_, offer) in &cancelled {
+        release_offer(&state, offer).await;
+    }
+
+    Json(CancelResponse {
+        cancelled: cancelled.into_iter().map(|(id, _)| id).collect(),
+    })
+}
@@ -0,0 +1,224 @@
+use crate::{
+    error::{ApiError, ErrorCode},
+    extract::Json,
+    offer::{Offer, OfferOptions, OfferUID, OrderType},
+    state::{escrow_funds, refund_escrow, release_offer, settle_trades, GState, Matches},
+    trade::{Trade, TradeUID},
+};
+use axum::{extract::State, Extension};
+use ccash_rs::CCashUser;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+/// A market ask sells to whatever the best bids are. With a `stop_price` it
+/// waits until the last trade price falls to it first.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateMarketAsk {
+    pub commodity_name: String,
+    pub item_amount: u64,
+    pub stop_price: Option<u64>,
+}
+
+/// A market bid buys at whatever the best asks are, spending no more than
+/// `max_total_cost` if it is given. With a `stop_price` it waits until the
+/// last trade price rises to it first, and `max_total_cost` is required as the
+/// cost can't be known in advance.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateMarketBid {
+    pub commodity_name: String,
+    pub item_amount: u64,
+    pub max_total_cost: Option<u64>,
+    pub stop_price: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarketOrderResponse {
+    pub offer_id: OfferUID,
+    pub offer: Offer,
+    pub trades: Vec<Trade>,
+}
+
+fn market_order_response(
+    state: &GState,
+    offer_id: OfferUID,
+    trade_ids: &[TradeUID],
+) -> Result<Json<MarketOrderResponse>, ApiError> {
+    let state = state.read();
+    let offer = state
+        .get_offers()
+        .get(&offer_id)
+        .map(|kv| kv.value().read().clone());
+
+    let Some(offer) = offer else {
+        return Err(ApiError::offer_not_found(offer_id));
+    };
+
+    Ok(Json(MarketOrderResponse {
+        offer_id,
+        offer,
+        trades: state.get_trades_from_ids(trade_ids),
+    }))
+}
+
+pub async fn create_market_ask(
+    Extension(ccash_user): Extension<CCashUser>,
+    State(state): State<GState>,
+    Json(CreateMarketAsk {
+        commodity_name,
+        item_amount,
+        stop_price,
+    }): Json<CreateMarketAsk>,
+) -> Result<Json<MarketOrderResponse>, ApiError> {
+    if item_amount == 0 {
+        return Err(ApiError::new(
+            ErrorCode::InvalidSize,
+            "item_amount cannot be 0",
+        ));
+    }
+
+    let options = OfferOptions {
+        stop_price,
+        ..OfferOptions::default()
+    };
+
+    if let Err(e) = options.validate(Utc::now()) {
+        return Err(ApiError::invalid_request(e));
+    }
+
+    let Some(commodity_id) = state.read().get_commodity_id(&commodity_name) else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    let user_id = state.write().get_or_add_user(&ccash_user);
+
+    let result = state.write().add_ask(
+        commodity_id,
+        user_id,
+        item_amount,
+        0,
+        OrderType::Market,
+        options,
+    );
+
+    let (
+        ask_id,
+        Matches {
+            trade_ids,
+            released,
+        },
+    ) = match result {
+        Ok(ids) => ids,
+        Err(e) => return Err(ApiError::from(e)),
+    };
+
+    if let Some(user) = state.write().get_users_mut().get_mut(&user_id) {
+        user.write().add_offer_id(ask_id);
+    }
+
+    settle_trades(&state, &trade_ids).await;
+
+    for offer in &released {
+        release_offer(&state, offer).await;
+    }
+
+    market_order_response(&state, ask_id, &trade_ids)
+}
+
+pub async fn create_market_bid(
+    Extension(ccash_user): Extension<CCashUser>,
+    State(state): State<GState>,
+    Json(CreateMarketBid {
+        commodity_name,
+        item_amount,
+        max_total_cost,
+        stop_price,
+    }): Json<CreateMarketBid>,
+) -> Result<Json<MarketOrderResponse>, ApiError> {
+    if item_amount == 0 {
+        return Err(ApiError::new(
+            ErrorCode::InvalidSize,
+            "item_amount cannot be 0",
+        ));
+    }
+
+    let options = OfferOptions {
+        stop_price,
+        ..OfferOptions::default()
+    };
+
+    if let Err(e) = options.validate(Utc::now()) {
+        return Err(ApiError::invalid_request(e));
+    }
+
+    let Some(commodity_id) = state.read().get_commodity_id(&commodity_name) else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    let user_id = state.write().get_or_add_user(&ccash_user);
+
+    // Escrow what the order would cost at the current prices, which is all
+    // it's allowed to spend even if the book moves before it executes. Stop
+    // bids execute later at unknown prices, so they escrow their whole budget.
+    let escrow = if stop_price.is_some() {
+        let Some(max_total_cost) = max_total_cost else {
+            return Err(ApiError::invalid_request(
+                "max_total_cost is required for stop market bids",
+            ));
+        };
+
+        max_total_cost
+    } else {
+        state
+            .read()
+            .get_market_buy_cost(commodity_id, user_id, item_amount)
+            .min(max_total_cost.unwrap_or(u64::MAX))
+    };
+
+    if escrow == 0 {
+        return Err(ApiError::new(
+            ErrorCode::NoLiquidity,
+            format!("No \"{commodity_name}\" items can be bought right now"),
+        ));
+    }
+
+    if let Err(e) = escrow_funds(&state, &ccash_user, escrow).await {
+        return Err(ApiError::escrow_failed(escrow, e));
+    }
+
+    let result = state.write().add_bid(
+        commodity_id,
+        user_id,
+        item_amount,
+        0,
+        OrderType::Market,
+        options,
+        Some(escrow),
+    );
+
+    let (
+        bid_id,
+        Matches {
+            trade_ids,
+            released,
+        },
+    ) = match result {
+        Ok(ids) => ids,
+        Err(e) => {
+            refund_escrow(&state, user_id, escrow).await;
+
+            return Err(ApiError::from(e));
+        },
+    };
+
+    if let Some(user) = state.write().get_users_mut().get_mut(&user_id) {
+        user.write().add_offer_id(bid_id);
+    }
+
+    settle_trades(&state, &trade_ids).await;
+
+    for offer in &released {
+        release_offer(&state, offer).await;
+    }
+
+    market_order_response(&state, bid_id, &trade_ids)
+}
@@ -1,24 +1,117 @@
+mod amend;
 mod ask;
 mod bid;
+mod cancel;
+mod market;
 
 use std::cmp::Ordering;
 
+pub use amend::*;
 pub use ask::*;
 pub use bid::*;
+pub use cancel::*;
+pub use market::*;
 
-use crate::{offer::Offer, state::GState};
-use axum::{
-    extract::{Path, Query, State},
-    Json,
+use crate::{
+    error::{ApiError, ErrorCode},
+    extract::{Json, Path, Query},
+    offer::{Offer, OfferStatus, OfferUID},
+    state::GState,
+    user::UserUID,
 };
+use axum::extract::State;
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
 
 pub const MAX_OFFER_RESPONSE: usize = 1000;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OfferResponse {
     pub message: String,
+    pub offer_id: OfferUID,
+    pub offer: Offer,
+}
+
+/// The size and price of a new limit offer. Older clients sent `total_cost` and
+/// `cost_per_item` instead of `item_amount` and `price_per_item`, which are
+/// still accepted as long as they describe a whole number of items, and agree
+/// with the new fields if both are sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct OfferSize {
+    #[serde(default)]
+    pub item_amount: Option<u64>,
+    #[serde(default)]
+    pub price_per_item: Option<u64>,
+    #[serde(default)]
+    pub total_cost: Option<u64>,
+    #[serde(default)]
+    pub cost_per_item: Option<u64>,
+}
+
+impl OfferSize {
+    /// The number of items and the price per item, or an error saying what is
+    /// wrong with the payload.
+    pub fn resolve(&self) -> Result<(u64, u64), ApiError> {
+        let legacy = match (self.total_cost, self.cost_per_item) {
+            (None, None) => None,
+            (Some(total_cost), Some(cost_per_item)) => {
+                if cost_per_item == 0 || total_cost % cost_per_item != 0 {
+                    return Err(ApiError::new(
+                        ErrorCode::InconsistentLegacyPayload,
+                        "total_cost must be a whole multiple of cost_per_item, use \
+                         item_amount and price_per_item instead",
+                    )
+                    .with_detail("total_cost", total_cost)
+                    .with_detail("cost_per_item", cost_per_item));
+                }
+
+                Some((total_cost / cost_per_item, cost_per_item))
+            },
+            _ =>
+                return Err(ApiError::new(
+                    ErrorCode::IncompleteLegacyPayload,
+                    "total_cost and cost_per_item must be sent together",
+                )),
+        };
+
+        let explicit = match (self.item_amount, self.price_per_item) {
+            (None, None) => None,
+            (Some(item_amount), Some(price_per_item)) =>
+                Some((item_amount, price_per_item)),
+            _ =>
+                return Err(ApiError::new(
+                    ErrorCode::IncompletePayload,
+                    "item_amount and price_per_item must be sent together",
+                )),
+        };
+
+        let (item_amount, price_per_item) = match (explicit, legacy) {
+            (Some(explicit), Some(legacy)) if explicit != legacy =>
+                return Err(ApiError::new(
+                    ErrorCode::ConflictingPayload,
+                    "total_cost and cost_per_item disagree with item_amount and \
+                     price_per_item",
+                )
+                .with_detail("item_amount", explicit.0)
+                .with_detail("price_per_item", explicit.1)
+                .with_detail("total_cost", self.total_cost)
+                .with_detail("cost_per_item", self.cost_per_item)),
+            (Some(size), _) | (None, Some(size)) => size,
+            (None, None) =>
+                return Err(ApiError::new(
+                    ErrorCode::MissingSize,
+                    "item_amount and price_per_item are required",
+                )),
+        };
+
+        if item_amount == 0 || price_per_item == 0 {
+            return Err(ApiError::new(
+                ErrorCode::InvalidSize,
+                "item_amount and price_per_item cannot be 0",
+            ));
+        }
+
+        Ok((item_amount, price_per_item))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -34,6 +127,10 @@ impl Default for OfferSortBy {
     fn default() -> Self { Self::DateDescending }
 }
 
+pub(crate) fn offer_status_filter(status: Option<OfferStatus>, offer: &Offer) -> bool {
+    status.is_none() || status == Some(offer.get_status())
+}
+
 pub(crate) fn offer_sort(sort_by: OfferSortBy, a: &Offer, b: &Offer) -> Ordering {
     match (sort_by, a, b) {
         (
@@ -95,9 +192,32 @@ pub(crate) fn offer_sort(sort_by: OfferSortBy, a: &Offer, b: &Offer) -> Ordering
     }
 }
 
+/// Checks that `offer_id` exists and was placed by `user_id`, before anything
+/// is done to it on the user's behalf.
+pub(crate) fn check_offer_owner(
+    state: &GState,
+    offer_id: OfferUID,
+    user_id: UserUID,
+) -> Result<(), ApiError> {
+    let owner_id = state
+        .read()
+        .get_offers()
+        .get(&offer_id)
+        .map(|kv| kv.value().read().get_user_id());
+
+    match owner_id {
+        None => Err(ApiError::offer_not_found(offer_id)),
+        Some(owner_id) if owner_id != user_id => Err(ApiError::forbidden(format!(
+            "Offer \"{offer_id}\" does not belong to this user"
+        ))),
+        Some(_) => Ok(()),
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OfferQueryParams {
     pub limit: Option<usize>,
+    pub status: Option<OfferStatus>,
     #[serde(flatten)]
     pub sort_by: Option<OfferSortBy>,
 }
@@ -106,16 +226,21 @@ impl Default for OfferQueryParams {
     fn default() -> Self {
         Self {
             limit: Some(100),
+            status: None,
             sort_by: Some(OfferSortBy::DateDescending),
         }
     }
 }
 
 pub async fn get_offers(
-    params: Option<Query<OfferQueryParams>>,
+    Query(params): Query<OfferQueryParams>,
     State(state): State<GState>,
-) -> Result<Json<Vec<Offer>>, Json<Value>> {
-    let Query(OfferQueryParams { limit, sort_by }) = params.unwrap_or_default();
+) -> Result<Json<Vec<Offer>>, ApiError> {
+    let OfferQueryParams {
+        limit,
+        status,
+        sort_by,
+    } = params;
     let mut limit = limit.unwrap_or(100);
     let sort_by = sort_by.unwrap_or(OfferSortBy::DateDescending);
 
@@ -129,7 +254,8 @@ pub async fn get_offers(
     let mut offers = offer_ids
         .iter()
         .map(|kv| kv.value().clone())
-        .map(|value| value.read().clone())
+        .map(|value| value.read().as_public())
+        .filter(|offer| offer_status_filter(status, offer))
         .collect::<Vec<_>>();
 
     offers.sort_by(|a, b| offer_sort(sort_by.clone(), a, b));
@@ -140,11 +266,15 @@ pub async fn get_offers(
 }
 
 pub async fn get_offers_for_user(
-    params: Option<Query<OfferQueryParams>>,
+    Query(params): Query<OfferQueryParams>,
     State(state): State<GState>,
     Path(username): Path<String>,
-) -> Result<Json<Vec<Offer>>, Json<Value>> {
-    let Query(OfferQueryParams { limit, sort_by }) = params.unwrap_or_default();
+) -> Result<Json<Vec<Offer>>, ApiError> {
+    let OfferQueryParams {
+        limit,
+        status,
+        sort_by,
+    } = params;
     let mut limit = limit.unwrap_or(100);
     let sort_by = sort_by.unwrap_or(OfferSortBy::DateDescending);
 
@@ -169,7 +299,8 @@ pub async fn get_offers_for_user(
             .iter()
             .filter_map(|id| state.get_offers().get(id))
             .map(|kv| kv.value().clone())
-            .map(|value| value.read().clone())
+            .map(|value| value.read().as_public())
+            .filter(|offer| offer_status_filter(status, offer))
             .collect::<Vec<_>>();
 
         offers.sort_by(|a, b| offer_sort(sort_by.clone(), a, b));
@@ -179,5 +310,5 @@ pub async fn get_offers_for_user(
         return Ok(Json(offers));
     }
 
-    Err(Json(json!({})))
+    Err(ApiError::user_not_found(username))
 }
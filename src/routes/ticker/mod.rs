@@ -0,0 +1,110 @@
+use crate::{
+    book::{PriceLevel, TradeStats},
+    commodity::{Commodity, CommodityUID, TradingMode},
+    error::ApiError,
+    extract::{Json, Path},
+    state::{AppState, GState},
+};
+use axum::extract::State;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// The top of a commodity's book alongside its most recent trading activity.
+/// The 24 hour figures only cover trades from the last 24 hours, and trades
+/// which failed to settle are ignored.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ticker {
+    pub commodity_id: CommodityUID,
+    pub commodity_name: String,
+    pub trading_mode: TradingMode,
+    pub best_bid: Option<PriceLevel>,
+    pub best_ask: Option<PriceLevel>,
+    pub spread: Option<u64>,
+    pub last_price_per_item: Option<u64>,
+    pub last_item_amount: Option<u64>,
+    pub last_trade_datetime: Option<DateTime<Utc>>,
+    pub volume_24h: u64,
+    pub high_24h: Option<u64>,
+    pub low_24h: Option<u64>,
+    pub change_24h: Option<i64>,
+}
+
+/// Builds the ticker for `commodity_id` from the trades its book keeps, so
+/// quotes don't have to look through every trade in the market.
+fn get_ticker(
+    state: &AppState,
+    commodity_id: CommodityUID,
+    commodity: &Commodity,
+) -> Ticker {
+    let (bids, asks) = state.get_book_depth(commodity_id, 1);
+    let best_bid = bids.first().copied();
+    let best_ask = asks.first().copied();
+    let spread = best_bid
+        .zip(best_ask)
+        .map(|(bid, ask)| ask.price_per_item.saturating_sub(bid.price_per_item));
+
+    let TradeStats {
+        last_trade,
+        volume,
+        high,
+        low,
+        open,
+    } = state.get_trade_stats(commodity_id, Utc::now() - Duration::hours(24));
+
+    let change_24h = open.zip(last_trade).and_then(|(open, (_, close, _))| {
+        let open = i64::try_from(open).ok()?;
+        let close = i64::try_from(close).ok()?;
+
+        Some(close - open)
+    });
+
+    Ticker {
+        commodity_id,
+        commodity_name: commodity.get_name().to_owned(),
+        trading_mode: commodity.get_trading_mode(),
+        best_bid,
+        best_ask,
+        spread,
+        last_price_per_item: last_trade.map(|(_, price_per_item, _)| price_per_item),
+        last_item_amount: last_trade.map(|(_, _, item_amount)| item_amount),
+        last_trade_datetime: last_trade.map(|(datetime, ..)| datetime),
+        volume_24h: volume,
+        high_24h: high,
+        low_24h: low,
+        change_24h,
+    }
+}
+
+pub async fn get_ticker_for_commodity(
+    State(state): State<GState>,
+    Path(commodity_name): Path<String>,
+) -> Result<Json<Ticker>, ApiError> {
+    let state = state.read();
+
+    let Some(commodity_id) = state.get_commodity_id(&commodity_name) else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    let Some(commodity) = state.get_commodities().get(&commodity_id).map(|kv| Arc::clone(kv.value())) else {
+        return Err(ApiError::commodity_not_found(commodity_name));
+    };
+
+    let ticker = get_ticker(&state, commodity_id, &commodity.read());
+
+    Ok(Json(ticker))
+}
+
+pub async fn get_tickers(State(state): State<GState>) -> Json<Vec<Ticker>> {
+    let state = state.read();
+
+    let mut tickers = state
+        .get_commodities()
+        .iter()
+        .map(|kv| get_ticker(&state, *kv.key(), &kv.value().read()))
+        .collect::<Vec<_>>();
+
+    tickers.sort_by(|a, b| a.commodity_name.cmp(&b.commodity_name));
+
+    Json(tickers)
+}
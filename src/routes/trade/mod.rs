@@ -0,0 +1,126 @@
+use super::OfferSortBy;
+use crate::{
+    commodity::CommodityUID,
+    error::ApiError,
+    extract::{Json, Path, Query},
+    state::GState,
+    trade::Trade,
+};
+use axum::extract::State;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use uuid::Uuid;
+
+pub const MAX_TRADE_RESPONSE: usize = 1000;
+
+pub(crate) fn trade_sort(sort_by: &OfferSortBy, a: &Trade, b: &Trade) -> Ordering {
+    let total_cost = |trade: &Trade| {
+        trade
+            .get_item_amount()
+            .saturating_mul(trade.get_price_per_item())
+    };
+
+    match sort_by {
+        OfferSortBy::DateDescending => b.get_datetime().cmp(&a.get_datetime()),
+        OfferSortBy::DateAscending => a.get_datetime().cmp(&b.get_datetime()),
+        OfferSortBy::TotalCostDescending => total_cost(b).cmp(&total_cost(a)),
+        OfferSortBy::TotalCostAscending => total_cost(a).cmp(&total_cost(b)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TradeQueryParams {
+    pub limit: Option<usize>,
+    #[serde(flatten)]
+    pub sort_by: Option<OfferSortBy>,
+}
+
+impl Default for TradeQueryParams {
+    fn default() -> Self {
+        Self {
+            limit: Some(100),
+            sort_by: Some(OfferSortBy::DateDescending),
+        }
+    }
+}
+
+/// Sorts and truncates `trades` according to the given query parameters.
+fn limit_trades(params: TradeQueryParams, mut trades: Vec<Trade>) -> Vec<Trade> {
+    let TradeQueryParams { limit, sort_by } = params;
+    let mut limit = limit.unwrap_or(100);
+    let sort_by = sort_by.unwrap_or(OfferSortBy::DateDescending);
+
+    if limit == 0 || limit > MAX_TRADE_RESPONSE {
+        limit = MAX_TRADE_RESPONSE;
+    }
+
+    trades.sort_by(|a, b| trade_sort(&sort_by, a, b));
+    trades.truncate(limit);
+
+    trades
+}
+
+pub async fn get_trades(
+    Query(params): Query<TradeQueryParams>,
+    State(state): State<GState>,
+) -> Json<Vec<Trade>> {
+    let state = state.read();
+
+    let trades = state
+        .get_trades()
+        .iter()
+        .map(|kv| kv.value().read().clone())
+        .collect::<Vec<_>>();
+
+    Json(limit_trades(params, trades))
+}
+
+pub async fn get_trades_for_user(
+    Query(params): Query<TradeQueryParams>,
+    State(state): State<GState>,
+    Path(username): Path<String>,
+) -> Result<Json<Vec<Trade>>, ApiError> {
+    let state = state.read();
+    let users = state.get_users();
+
+    let Some(user_id) = users
+        .iter()
+        .find(|kv| kv.value().read().get_username() == username)
+        .map(|kv| *kv.key())
+    else {
+        return Err(ApiError::user_not_found(username));
+    };
+
+    let trades = state
+        .get_trades()
+        .iter()
+        .map(|kv| kv.value().read().clone())
+        .filter(|trade| {
+            trade.get_buyer_id() == user_id || trade.get_seller_id() == user_id
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Json(limit_trades(params, trades)))
+}
+
+pub async fn get_trades_for_commodity(
+    Query(params): Query<TradeQueryParams>,
+    State(state): State<GState>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Vec<Trade>>, ApiError> {
+    let state = state.read();
+    let commodity_id = CommodityUID(id);
+
+    if !state.get_commodities().contains_key(&commodity_id) {
+        return Err(ApiError::commodity_not_found(id));
+    }
+
+    let trades = state
+        .get_trades()
+        .iter()
+        .map(|kv| kv.value().read().clone())
+        .filter(|trade| trade.get_commodity_id() == commodity_id)
+        .collect::<Vec<_>>();
+
+    Ok(Json(limit_trades(params, trades)))
+}
@@ -1,30 +1,29 @@
 use crate::{
     commodity::{Commodity, CommodityUID},
+    error::ApiError,
+    extract::{Json, Path},
     state::GState,
     user::{User, UserUID},
 };
-use axum::{
-    extract::{Path, State},
-    Json,
-};
-use serde_json::Value;
+use axum::extract::State;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Holding {
+    pub commodity_id: CommodityUID,
+    pub commodity_name: String,
+    pub item_amount: u64,
+    pub reserved_amount: u64,
+}
+
 pub async fn get_user_from_id(
     Path(id): Path<Uuid>,
     State(state): State<GState>,
-) -> Result<Json<User>, Json<Value>> {
+) -> Result<Json<User>, ApiError> {
     let state = state.read();
     let Some(kv) = state.get_users().get(&UserUID(id)) else {
-        // let response = Response::builder()
-        //     .status(StatusCode::NOT_FOUND)
-        //     .body(
-        //         Json(json!({"message" : format!("ID \"{id}\" not found")}))
-        //     ).unwrap();
-
-        // return Err(response);
-
-        todo!()
+        return Err(ApiError::user_not_found(id));
     };
 
     let v = kv.value().clone();
@@ -36,10 +35,10 @@ pub async fn get_user_from_id(
 pub async fn get_commodity_from_id(
     Path(id): Path<Uuid>,
     State(state): State<GState>,
-) -> Result<Json<Commodity>, Json<Value>> {
+) -> Result<Json<Commodity>, ApiError> {
     let state = state.read();
     let Some(kv) = state.get_commodities().get(&CommodityUID(id)) else {
-        todo!()
+        return Err(ApiError::commodity_not_found(id));
     };
 
     let v = kv.value().clone();
@@ -47,3 +46,37 @@ pub async fn get_commodity_from_id(
 
     Ok(Json(v))
 }
+
+pub async fn get_holdings_for_user(
+    Path(username): Path<String>,
+    State(state): State<GState>,
+) -> Result<Json<Vec<Holding>>, ApiError> {
+    let state = state.read();
+
+    let Some(user_id) = state
+        .get_users()
+        .iter()
+        .find(|kv| kv.value().read().get_username() == username)
+        .map(|kv| *kv.key())
+    else {
+        return Err(ApiError::user_not_found(username));
+    };
+
+    let holdings = state
+        .get_commodities()
+        .iter()
+        .filter_map(|kv| {
+            let commodity = kv.value().read();
+            let item_amount = commodity.get_holding(user_id);
+
+            (item_amount > 0).then(|| Holding {
+                commodity_id: *kv.key(),
+                commodity_name: commodity.get_name().to_owned(),
+                item_amount,
+                reserved_amount: commodity.get_reserved(user_id),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Json(holdings))
+}
@@ -0,0 +1,261 @@
+use super::{AppState, Matches};
+use crate::{
+    commodity::CommodityUID,
+    offer::{Fill, Offer, OfferUID},
+    trade::TradeUID,
+};
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use std::{cmp::Reverse, sync::Arc};
+use uuid::Uuid;
+
+/// The price at which the most items would trade if every ask priced at or
+/// below it were matched against every bid priced at or above it, along with
+/// how many items that is. `asks` and `bids` are the price and remaining amount
+/// of each offer.
+///
+/// Ties go to the price which leaves the fewest items unmatched on either side,
+/// then to the price closest to `last_price`, and then to the lowest price.
+/// Returns `None` if the book doesn't cross.
+fn get_clearing_price(
+    asks: &[(u64, u64)],
+    bids: &[(u64, u64)],
+    last_price: Option<u64>,
+) -> Option<(u64, u64)> {
+    let total = |offers: &[(u64, u64)], included: &dyn Fn(u64) -> bool| {
+        offers
+            .iter()
+            .filter(|(price_per_item, _)| included(*price_per_item))
+            .map(|(_, item_amount)| *item_amount)
+            .fold(0, u64::saturating_add)
+    };
+
+    asks.iter()
+        .chain(bids)
+        .map(|(price_per_item, _)| {
+            let price_per_item = *price_per_item;
+            let supply = total(asks, &|ask_price| ask_price <= price_per_item);
+            let demand = total(bids, &|bid_price| bid_price >= price_per_item);
+
+            (price_per_item, supply.min(demand), supply.abs_diff(demand))
+        })
+        .filter(|(_, item_amount, _)| *item_amount > 0)
+        .min_by_key(|(price_per_item, item_amount, imbalance)| {
+            let distance = last_price.map_or(0, |last| last.abs_diff(*price_per_item));
+
+            (Reverse(*item_amount), *imbalance, distance, *price_per_item)
+        })
+        .map(|(price_per_item, item_amount, _)| (price_per_item, item_amount))
+}
+
+impl AppState {
+    /// Collects the offers on one side of `commodity_id`'s book which can take
+    /// part in an auction, best price first and then earliest first.
+    /// All-or-none offers left over from continuous trading sit auctions out,
+    /// as auctions can't promise to fill them completely.
+    fn get_auction_side(
+        &self,
+        commodity_id: CommodityUID,
+        asks: bool,
+        now: DateTime<Utc>,
+    ) -> Vec<(OfferUID, Arc<RwLock<Offer>>)> {
+        let Some(book) = self.books.get(&commodity_id) else {
+            return Vec::new();
+        };
+
+        let offer_ids = if asks {
+            book.get_ask_levels()
+                .flat_map(|(_, level)| level.iter().map(|(_, offer_id)| *offer_id))
+                .collect::<Vec<_>>()
+        } else {
+            book.get_bid_levels()
+                .flat_map(|(_, level)| level.iter().map(|(_, offer_id)| *offer_id))
+                .collect::<Vec<_>>()
+        };
+
+        drop(book);
+
+        offer_ids
+            .into_iter()
+            .filter_map(|offer_id| {
+                let offer = self
+                    .data
+                    .offers
+                    .get(&offer_id)
+                    .map(|kv| Arc::clone(kv.value()))?;
+
+                let takes_part = {
+                    let offer = offer.read();
+
+                    offer.get_status().is_live()
+                        && !offer.has_expired(now)
+                        && !offer.get_options().all_or_none
+                };
+
+                takes_part.then_some((offer_id, offer))
+            })
+            .collect()
+    }
+
+    /// Runs an auction for `commodity_id`, filling every crossing ask and bid
+    /// at the single clearing price which trades the most items. Offers are
+    /// filled in price and then time priority, so offers priced exactly at the
+    /// clearing price may be only partially filled. Offers from the same user
+    /// never trade with each other, and are cancelled instead according to the
+    /// self-trade prevention policy. The auction stops early if one of its
+    /// trades halts trading.
+    ///
+    /// Each trade's aggressor is whichever of its two offers was placed last.
+    /// Stop offers triggered by the auction are activated afterwards.
+    pub(crate) fn run_auction(
+        &mut self,
+        commodity_id: CommodityUID,
+        now: DateTime<Utc>,
+    ) -> Matches {
+        let asks = self.get_auction_side(commodity_id, true, now);
+        let bids = self.get_auction_side(commodity_id, false, now);
+
+        let amounts = |offers: &[(OfferUID, Arc<RwLock<Offer>>)]| {
+            offers
+                .iter()
+                .map(|(_, offer)| {
+                    let offer = offer.read();
+
+                    (offer.get_price_per_item(), offer.get_remaining_amount())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let last_price = self
+            .books
+            .get(&commodity_id)
+            .and_then(|book| book.get_last_price());
+
+        let Some((price_per_item, item_amount)) = get_clearing_price(&amounts(&asks), &amounts(&bids), last_price) else {
+            return self.trigger_stops(commodity_id);
+        };
+
+        tracing::info!(
+            "Auction for {commodity_id} clearing up to {item_amount} item(s) at \
+             {price_per_item} CSH each"
+        );
+
+        let mut trade_ids = Vec::new();
+        let mut released = Vec::new();
+
+        'auction: for (bid_id, bid) in &bids {
+            let mut bid = bid.write();
+
+            if bid.get_price_per_item() < price_per_item {
+                break;
+            }
+
+            for (ask_id, ask) in &asks {
+                if bid.get_remaining_amount() == 0 || !bid.get_status().is_live() {
+                    break;
+                }
+
+                let mut ask = ask.write();
+
+                if ask.get_price_per_item() > price_per_item {
+                    break;
+                }
+
+                let item_amount =
+                    bid.get_remaining_amount().min(ask.get_remaining_amount());
+
+                if item_amount == 0 || !ask.get_status().is_live() {
+                    continue;
+                }
+
+                if ask.get_user_id() == bid.get_user_id() {
+                    let (offer_id, offer, resting_id, resting) =
+                        if bid.get_datetime() >= ask.get_datetime() {
+                            (*bid_id, &mut *bid, *ask_id, &mut *ask)
+                        } else {
+                            (*ask_id, &mut *ask, *bid_id, &mut *bid)
+                        };
+
+                    // Unlike an incoming offer, the newer offer is also resting
+                    // in the book, so it has to be taken out here.
+                    if self.prevent_self_trade(
+                        offer_id,
+                        offer,
+                        resting_id,
+                        resting,
+                        &mut released,
+                    ) {
+                        self.remove_from_book(offer_id, offer);
+                        self.release_items(offer);
+                        released.push(offer.clone());
+                    }
+
+                    continue;
+                }
+
+                let datetime = Utc::now();
+                let trade_id = TradeUID(Uuid::new_v4());
+
+                bid.fill(trade_id, *ask_id, item_amount, price_per_item, datetime);
+                ask.fill(trade_id, *bid_id, item_amount, price_per_item, datetime);
+
+                self.requeue(*bid_id, &mut bid, datetime);
+                self.requeue(*ask_id, &mut ask, datetime);
+
+                let (offer_id, offer, resting_id, resting) =
+                    if bid.get_datetime() >= ask.get_datetime() {
+                        (*bid_id, &*bid, *ask_id, &*ask)
+                    } else {
+                        (*ask_id, &*ask, *bid_id, &*bid)
+                    };
+
+                let fill = Fill {
+                    trade_id,
+                    counter_offer_id: resting_id,
+                    datetime,
+                    item_amount,
+                    price_per_item,
+                };
+
+                trade_ids.push(self.record_trade(offer_id, offer, resting, fill));
+
+                if self.is_paused(commodity_id) {
+                    break 'auction;
+                }
+            }
+        }
+
+        let mut matches = Matches {
+            trade_ids,
+            released,
+        };
+
+        matches.append(self.trigger_stops(commodity_id));
+
+        matches
+    }
+
+    /// Runs the auction for every auctioned commodity whose interval has
+    /// passed since its last one.
+    pub(crate) fn run_due_auctions(&mut self, now: DateTime<Utc>) -> Matches {
+        let due = self
+            .data
+            .commodities
+            .iter()
+            .filter(|kv| kv.value().read().is_auction_due(now))
+            .map(|kv| *kv.key())
+            .collect::<Vec<_>>();
+
+        let mut matches = Matches::default();
+
+        for commodity_id in due {
+            matches.append(self.run_auction(commodity_id, now));
+
+            if let Some(commodity) = self.data.commodities.get(&commodity_id) {
+                commodity.value().write().set_last_auction(now);
+            }
+        }
+
+        matches
+    }
+}
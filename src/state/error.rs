@@ -0,0 +1,96 @@
+use crate::{
+    commodity::CommodityUID,
+    offer::{OfferStatus, OfferUID},
+};
+use std::fmt;
+
+/// Why the market refused to make a change. Each kind of refusal is reported
+/// to clients with its own error code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    CommodityNotFound(CommodityUID),
+    OfferNotFound(OfferUID),
+    NotOfferOwner(OfferUID),
+    OfferClosed(OfferUID, OfferStatus),
+    /// Settings for a commodity which don't make sense.
+    InvalidSettings(String),
+    InvalidAmendment(String),
+    InvalidIssuance(String),
+    InsufficientHoldings {
+        commodity_name: String,
+        item_amount: u64,
+        available: u64,
+    },
+    TradingHalted {
+        commodity_name: String,
+        reason: String,
+    },
+    TradingClosed(String),
+    /// Only offers which rest in the book can be placed during pre-open.
+    PreOpenRestricted(String),
+    TickSize(String),
+    LotSize(String),
+    OrderValue(String),
+    PriceBand(String),
+    /// Only offers which rest in the book, and aren't all-or-none, can be
+    /// placed for auctioned commodities.
+    AuctionRestricted,
+    PostOnlyWouldTrade,
+    NotHalted(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CommodityNotFound(commodity_id) =>
+                write!(f, "Commodity \"{commodity_id}\" not found"),
+            Self::OfferNotFound(offer_id) => write!(f, "Offer \"{offer_id}\" not found"),
+            Self::NotOfferOwner(offer_id) =>
+                write!(f, "Offer \"{offer_id}\" does not belong to this user"),
+            Self::OfferClosed(offer_id, status) => write!(
+                f,
+                "Offer \"{offer_id}\" is {status:?} and can no longer be changed"
+            ),
+            Self::InvalidSettings(message)
+            | Self::InvalidAmendment(message)
+            | Self::InvalidIssuance(message)
+            | Self::TickSize(message)
+            | Self::LotSize(message)
+            | Self::OrderValue(message)
+            | Self::PriceBand(message) => write!(f, "{message}"),
+            Self::InsufficientHoldings {
+                commodity_name,
+                item_amount,
+                available,
+            } => write!(
+                f,
+                "Cannot sell {item_amount} \"{commodity_name}\" item(s), only \
+                 {available} are available"
+            ),
+            Self::TradingHalted {
+                commodity_name,
+                reason,
+            } => write!(f, "Trading in \"{commodity_name}\" is halted: {reason}"),
+            Self::TradingClosed(commodity_name) =>
+                write!(f, "\"{commodity_name}\" is closed for trading"),
+            Self::PreOpenRestricted(commodity_name) => write!(
+                f,
+                "Only good-till-cancelled or good-till-date limit offers can be placed \
+                 while \"{commodity_name}\" is in pre-open"
+            ),
+            Self::AuctionRestricted => write!(
+                f,
+                "Only good-till-cancelled or good-till-date limit offers without \
+                 all_or_none can be placed for auctioned commodities"
+            ),
+            Self::PostOnlyWouldTrade => write!(
+                f,
+                "Post-only offer would trade immediately with an offer in the book"
+            ),
+            Self::NotHalted(commodity_name) =>
+                write!(f, "Trading in \"{commodity_name}\" is not halted"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
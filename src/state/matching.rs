@@ -0,0 +1,483 @@
+use super::{AppState, Matches};
+use crate::{
+    commodity::CommodityUID,
+    config::SelfTradePrevention,
+    offer::{Fill, Offer, OfferStatus, OfferUID, OrderType, TimeInForce},
+    trade::{Trade, TradeSide, TradeUID},
+};
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use std::sync::Arc;
+use uuid::Uuid;
+
+impl AppState {
+    /// Collects the resting offers that `offer` can trade against from its
+    /// commodity's book, in the order in which they should be matched. Only
+    /// as many offers as are needed to fill `offer` are collected, and offers
+    /// which have passed their expiry but haven't been expired yet are skipped.
+    pub(super) fn crossing_offers(
+        &self,
+        offer: &Offer,
+    ) -> Vec<(OfferUID, Arc<RwLock<Offer>>)> {
+        let Some(book) = self.books.get(&offer.get_commodity_id()) else {
+            return Vec::new();
+        };
+
+        let now = Utc::now();
+        let mut wanted = offer.get_remaining_amount();
+        let mut crossing = Vec::new();
+
+        for resting_id in book.crossing(offer) {
+            if wanted == 0 {
+                break;
+            }
+
+            let Some(resting) = self.data.offers.get(&resting_id).map(|kv| Arc::clone(kv.value())) else {
+                continue;
+            };
+
+            let (user_id, remaining, all_or_none) = {
+                let resting = resting.read();
+
+                if !resting.get_status().is_live() || resting.has_expired(now) {
+                    continue;
+                }
+
+                (
+                    resting.get_user_id(),
+                    resting.get_remaining_amount(),
+                    resting.get_options().all_or_none,
+                )
+            };
+
+            // The user's own offers never trade with `offer`, and neither do
+            // all-or-none offers too big for it to fill, so they don't count
+            // towards filling it.
+            if user_id != offer.get_user_id() && (!all_or_none || remaining <= wanted) {
+                wanted = wanted.saturating_sub(remaining);
+            }
+
+            crossing.push((resting_id, resting));
+        }
+
+        crossing
+    }
+
+    /// How many of the items `offer` still wants to trade could be matched
+    /// against the book right now, taking self-trade prevention and all-or-none
+    /// offers into account.
+    pub(super) fn get_fillable_amount(&self, offer: &Offer) -> u64 {
+        let wanted = offer.get_remaining_amount();
+        let mut fillable: u64 = 0;
+
+        for (_, resting) in self.crossing_offers(offer) {
+            let resting = resting.read();
+            let remaining = resting.get_remaining_amount();
+
+            if resting.get_user_id() == offer.get_user_id() {
+                if self.self_trade_prevention != SelfTradePrevention::CancelOldest {
+                    break;
+                }
+            } else if !resting.get_options().all_or_none
+                || remaining <= wanted.saturating_sub(fillable)
+            {
+                fillable = fillable.saturating_add(remaining);
+            }
+        }
+
+        fillable.min(wanted)
+    }
+
+    /// Whether `offer` would trade with anything in the book if it were
+    /// matched now. Offers for auction commodities never trade until the next
+    /// auction.
+    pub(super) fn would_cross(&self, offer: &Offer) -> bool {
+        !self.is_auction(offer.get_commodity_id())
+            && !self.crossing_offers(offer).is_empty()
+    }
+
+    /// Stops `offer` from trading against `resting`, another offer from the
+    /// same user, according to the self-trade prevention policy. Returns
+    /// whether `offer` was cancelled, and adds `resting` to `released` if it
+    /// was.
+    pub(super) fn prevent_self_trade(
+        &self,
+        offer_id: OfferUID,
+        offer: &mut Offer,
+        resting_id: OfferUID,
+        resting: &mut Offer,
+        released: &mut Vec<Offer>,
+    ) -> bool {
+        let datetime = Utc::now();
+        let policy = self.self_trade_prevention;
+
+        tracing::info!(
+            "Prevented offer {offer_id} from trading against offer {resting_id} from \
+             the same user ({policy:?})"
+        );
+
+        if policy != SelfTradePrevention::CancelNewest
+            && resting.transition(OfferStatus::Cancelled, datetime)
+        {
+            self.remove_from_book(resting_id, resting);
+            self.release_items(resting);
+            released.push(resting.clone());
+        }
+
+        policy != SelfTradePrevention::CancelOldest
+            && offer.transition(OfferStatus::Cancelled, datetime)
+    }
+
+    /// Moves a resting offer which has just been filled to where it now
+    /// belongs: out of the book if it can no longer trade, or to the back of
+    /// its price level if it is an iceberg which has shown more items.
+    pub(super) fn requeue(
+        &self,
+        offer_id: OfferUID,
+        offer: &mut Offer,
+        now: DateTime<Utc>,
+    ) {
+        if !offer.get_status().is_live() {
+            self.remove_from_book(offer_id, offer);
+        } else if offer.replenish(now) {
+            self.remove_from_book(offer_id, offer);
+            self.add_to_book(offer_id, offer);
+        }
+    }
+
+    /// Records the trade made by `offer` filling against `resting`, and moves
+    /// the commodity's last trade price to it.
+    pub(super) fn record_trade(
+        &self,
+        offer_id: OfferUID,
+        offer: &Offer,
+        resting: &Offer,
+        fill: Fill,
+    ) -> TradeUID {
+        let Fill {
+            trade_id,
+            counter_offer_id,
+            item_amount,
+            price_per_item,
+            datetime,
+        } = fill;
+
+        // Market bids escrow exactly what they spend, so there's never any
+        // price improvement to refund.
+        let bid_price_per_item = if offer.is_ask() {
+            resting.get_price_per_item()
+        } else if offer.get_order_type() == OrderType::Market {
+            price_per_item
+        } else {
+            offer.get_price_per_item()
+        };
+
+        let (ask_id, bid_id, seller_id, buyer_id, aggressor) = if offer.is_ask() {
+            (
+                offer_id,
+                counter_offer_id,
+                offer.get_user_id(),
+                resting.get_user_id(),
+                TradeSide::Sell,
+            )
+        } else {
+            (
+                counter_offer_id,
+                offer_id,
+                resting.get_user_id(),
+                offer.get_user_id(),
+                TradeSide::Buy,
+            )
+        };
+
+        tracing::info!(
+            "Matched {item_amount} item(s) of {} at {price_per_item} CSH each (ask \
+             {ask_id}, bid {bid_id})",
+            offer.get_commodity_id()
+        );
+
+        let trade = Trade::new(
+            buyer_id,
+            seller_id,
+            offer.get_commodity_id(),
+            ask_id,
+            bid_id,
+            item_amount,
+            price_per_item,
+            bid_price_per_item - price_per_item,
+            aggressor,
+            datetime,
+        );
+
+        self.data
+            .trades
+            .insert(trade_id, Arc::new(RwLock::new(trade)));
+        self.books
+            .entry(offer.get_commodity_id())
+            .or_default()
+            .record_price(datetime, price_per_item, item_amount);
+        self.check_circuit_breaker(offer.get_commodity_id(), price_per_item, datetime);
+
+        trade_id
+    }
+
+    /// Halts trading in `commodity_id` if a trade at `price_per_item` has moved
+    /// the price too far within its circuit breaker's window.
+    fn check_circuit_breaker(
+        &self,
+        commodity_id: CommodityUID,
+        price_per_item: u64,
+        now: DateTime<Utc>,
+    ) {
+        let Some(commodity) = self.data.commodities.get(&commodity_id).map(|kv| Arc::clone(kv.value())) else {
+            return;
+        };
+
+        let circuit_breaker = commodity.read().get_circuit_breaker();
+        let since = circuit_breaker.get_window_start(now);
+
+        let range = self.books.get_mut(&commodity_id).and_then(|mut book| {
+            book.prune_prices(since);
+            book.get_price_range(since)
+        });
+
+        let Some(breached) = range.and_then(|range| circuit_breaker.get_breached_price(price_per_item, range)) else {
+            return;
+        };
+
+        let mut commodity = commodity.write();
+
+        if commodity.is_halted() {
+            return;
+        }
+
+        let reason = format!(
+            "Price moved from {breached} to {price_per_item} CSH within {} seconds",
+            circuit_breaker.window_seconds
+        );
+
+        tracing::warn!("Trading in {commodity_id} halted: {reason}");
+
+        commodity.halt(reason, now);
+    }
+
+    /// Crosses `offer` against the opposite side of its commodity's book until
+    /// it is either completely filled or no crossing offers remain. Resting
+    /// iceberg offers only trade the items they are showing at a time. Trades
+    /// always happen at the resting offer's price, and are recorded in the
+    /// market's trade history. Market bids also stop matching before their
+    /// total cost would exceed their budget.
+    ///
+    /// Offers from the same user never trade with each other, and are
+    /// cancelled instead according to the self-trade prevention policy.
+    /// All-or-none offers don't match at all unless they can be filled
+    /// completely, and offers for auction commodities only match in auctions.
+    /// Nothing matches while trading is halted or the commodity isn't open, and
+    /// matching stops as soon as the circuit breaker halts trading.
+    pub(super) fn match_offer(
+        &mut self,
+        offer_id: OfferUID,
+        offer: &mut Offer,
+    ) -> Matches {
+        if self.is_auction(offer.get_commodity_id())
+            || self.is_paused(offer.get_commodity_id())
+            || offer.get_options().all_or_none
+                && self.get_fillable_amount(offer) < offer.get_remaining_amount()
+        {
+            return Matches::default();
+        }
+
+        let mut trade_ids = Vec::new();
+        let mut released = Vec::new();
+        let mut budget = (!offer.is_ask() && offer.get_order_type() == OrderType::Market)
+            .then(|| offer.get_escrowed_funds());
+
+        // Iceberg offers only show part of their size at a time, and go to
+        // the back of the queue when they replenish, so keep collecting the
+        // crossing offers again while matching makes progress.
+        'matching: loop {
+            let mut progressed = false;
+
+            for (resting_id, resting) in self.crossing_offers(offer) {
+                let remaining = offer.get_remaining_amount();
+                if remaining == 0 {
+                    break 'matching;
+                }
+
+                let mut resting = resting.write();
+
+                if resting.get_user_id() == offer.get_user_id() {
+                    if self.prevent_self_trade(
+                        offer_id,
+                        offer,
+                        resting_id,
+                        &mut resting,
+                        &mut released,
+                    ) {
+                        break 'matching;
+                    }
+
+                    progressed = true;
+                    continue;
+                }
+
+                // An iceberg showing nothing can't trade until it replenishes,
+                // but the offers behind it still can.
+                let visible_amount = resting.get_visible_amount();
+                if visible_amount == 0 {
+                    continue;
+                }
+
+                let price_per_item = resting.get_price_per_item();
+                let mut item_amount = remaining.min(visible_amount);
+
+                if let Some(budget) = budget {
+                    let affordable =
+                        budget.checked_div(price_per_item).unwrap_or(u64::MAX);
+                    item_amount = item_amount.min(affordable);
+                }
+
+                // Resting offers are in price order, so if this one is unaffordable
+                // then so are the rest.
+                if item_amount == 0 {
+                    break 'matching;
+                }
+
+                if resting.get_options().all_or_none
+                    && item_amount < resting.get_remaining_amount()
+                {
+                    continue;
+                }
+
+                budget = budget.map(|budget| {
+                    budget.saturating_sub(item_amount.saturating_mul(price_per_item))
+                });
+                let datetime = Utc::now();
+                let trade_id = TradeUID(Uuid::new_v4());
+                let fill = Fill {
+                    trade_id,
+                    counter_offer_id: resting_id,
+                    datetime,
+                    item_amount,
+                    price_per_item,
+                };
+
+                offer.fill(trade_id, resting_id, item_amount, price_per_item, datetime);
+                resting.fill(trade_id, offer_id, item_amount, price_per_item, datetime);
+
+                progressed = true;
+
+                self.requeue(resting_id, &mut resting, datetime);
+
+                trade_ids.push(self.record_trade(offer_id, offer, &resting, fill));
+
+                if self.is_paused(offer.get_commodity_id()) {
+                    break 'matching;
+                }
+            }
+
+            if !progressed {
+                break;
+            }
+        }
+
+        // An iceberg can trade more than it shows when it is the incoming
+        // offer, so it shows the next part of its reserve before it rests.
+        offer.replenish(Utc::now());
+
+        Matches {
+            trade_ids,
+            released,
+        }
+    }
+
+    /// Matches an offer which has just become open, cancelling whatever is left
+    /// of it afterwards if it doesn't rest (market, immediate-or-cancel and
+    /// fill-or-kill offers) and adding it to the book if it does. Fill-or-kill
+    /// offers which can't be filled completely are cancelled without matching
+    /// at all, and post-only offers which would match are cancelled instead.
+    /// The offer is released if it can no longer trade.
+    pub(super) fn execute_offer(
+        &mut self,
+        offer_id: OfferUID,
+        offer: &mut Offer,
+    ) -> Matches {
+        let options = offer.get_options();
+        let mut matches = if options.post_only {
+            if self.would_cross(offer) {
+                offer.transition(OfferStatus::Cancelled, Utc::now());
+            }
+
+            Matches::default()
+        } else if options.time_in_force != TimeInForce::FillOrKill
+            || self.get_fillable_amount(offer) >= offer.get_remaining_amount()
+        {
+            self.match_offer(offer_id, offer)
+        } else {
+            Matches::default()
+        };
+
+        if !offer.rests() {
+            offer.transition(OfferStatus::Cancelled, Utc::now());
+        }
+
+        if offer.get_status().is_live() {
+            self.add_to_book(offer_id, offer);
+        } else {
+            self.release_items(offer);
+            matches.released.push(offer.clone());
+        }
+
+        matches
+    }
+
+    /// Activates and matches every dormant stop offer for `commodity_id` which
+    /// its last trade price has triggered. Trades made by triggered offers move
+    /// the last trade price, so this repeats until nothing else triggers. Stop
+    /// offers stay dormant while trading is halted or the commodity isn't open.
+    pub(super) fn trigger_stops(&mut self, commodity_id: CommodityUID) -> Matches {
+        let mut matches = Matches::default();
+
+        loop {
+            if self.is_paused(commodity_id) {
+                break;
+            }
+
+            let triggered = self
+                .books
+                .get_mut(&commodity_id)
+                .map(|mut book| book.take_triggered())
+                .unwrap_or_default();
+
+            if triggered.is_empty() {
+                break;
+            }
+
+            for offer_id in triggered {
+                let Some(offer_lock) = self.data.offers.get(&offer_id).map(|kv| Arc::clone(kv.value())) else {
+                    continue;
+                };
+
+                let mut offer = offer_lock.read().clone();
+
+                // An earlier stop offer may have halted trading, in which case
+                // the rest go back to waiting.
+                if self.is_paused(commodity_id) {
+                    self.add_to_book(offer_id, &offer);
+                    continue;
+                }
+
+                if !offer.transition(OfferStatus::Open, Utc::now()) {
+                    continue;
+                }
+
+                tracing::info!("Stop offer {offer_id} triggered");
+
+                matches.append(self.execute_offer(offer_id, &mut offer));
+                *offer_lock.write() = offer;
+            }
+        }
+
+        matches
+    }
+}
@@ -0,0 +1,2105 @@
+mod auction;
+mod error;
+mod matching;
+mod settlement;
+
+use crate::{
+    book::{Level, OrderBook, PriceLevel, TradeStats},
+    commodity::{
+        CircuitBreaker, Commodity, CommodityUID, ReferencePrice, TradingMode,
+        TradingRules, TradingSchedule, TradingSession,
+    },
+    config::{Config, SelfTradePrevention},
+    offer::{Offer, OfferOptions, OfferStatus, OfferUID, OrderType},
+    trade::{Settlement, Trade, TradeUID},
+    user::{User, UserUID},
+};
+use anyhow::{Error, Result};
+use ccash_rs::{methods as m, CCashSession, CCashUser};
+use chrono::{DateTime, SecondsFormat, Utc};
+use dashmap::DashMap;
+use directories::ProjectDirs;
+use flate2::{bufread::GzDecoder, write::GzEncoder, Compression};
+use parking_lot::RwLock;
+use rayon::prelude::{IntoParallelRefIterator, *};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs::{create_dir_all, rename, File},
+    io::{BufReader, BufWriter, Read},
+    path::PathBuf,
+    sync::Arc,
+};
+use uuid::Uuid;
+
+pub(crate) use error::StateError;
+pub(crate) use settlement::{
+    escrow_funds, refund_escrow, release_offer, settle_pending_trades, settle_trades,
+};
+
+pub type GState = Arc<RwLock<AppState>>;
+
+/// The trading hours of a commodity and where it currently is in them.
+#[derive(Serialize)]
+pub struct CommoditySession {
+    commodity_name: String,
+    session: TradingSession,
+    schedule: Option<TradingSchedule>,
+}
+
+#[derive(Serialize)]
+pub struct AppProperties {
+    ledger_host: String,
+    market_username: String,
+    sessions: Vec<CommoditySession>,
+}
+
+/// The outcome of matching an offer against the book: the trades it made, and
+/// the offers which stopped trading along the way, such as those cancelled by
+/// self-trade prevention. Those offers may still have funds escrowed which need
+/// refunding.
+#[derive(Default)]
+pub(crate) struct Matches {
+    pub(crate) trade_ids: Vec<TradeUID>,
+    pub(crate) released: Vec<Offer>,
+}
+
+impl Matches {
+    fn append(&mut self, mut other: Matches) {
+        self.trade_ids.append(&mut other.trade_ids);
+        self.released.append(&mut other.released);
+    }
+}
+
+/// The result of amending an offer. `escrow_before` and `escrow_after` are the
+/// CSH the offer needed escrowed before and after the amendment.
+pub(crate) struct Amendment {
+    pub(crate) trade_ids: Vec<TradeUID>,
+    pub(crate) released: Vec<Offer>,
+    pub(crate) escrow_before: u64,
+    pub(crate) escrow_after: u64,
+}
+
+pub(crate) type Books = DashMap<CommodityUID, OrderBook>;
+pub(crate) type Commodities = DashMap<CommodityUID, Arc<RwLock<Commodity>>>;
+pub(crate) type Offers = DashMap<OfferUID, Arc<RwLock<Offer>>>;
+pub(crate) type Trades = DashMap<TradeUID, Arc<RwLock<Trade>>>;
+pub(crate) type Users = DashMap<UserUID, Arc<RwLock<User>>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Data {
+    pub(super) commodities: Commodities,
+    pub(super) offers: Offers,
+    #[serde(default)]
+    pub(super) trades: Trades,
+    pub(super) users: Users,
+}
+
+/// Brings data saved by older versions up to date before it is deserialized.
+/// Offers saved before they could be partially filled have no remaining amount,
+/// as all of their items were still for sale.
+fn migrate_data(data: &mut Value) {
+    let Some(offers) = data.get_mut("offers").and_then(Value::as_object_mut) else {
+        return;
+    };
+
+    for offer in offers.values_mut().filter_map(Value::as_object_mut) {
+        if !offer.contains_key("remaining_amount") {
+            if let Some(item_amount) = offer.get("item_amount").cloned() {
+                offer.insert("remaining_amount".to_owned(), item_amount);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AppState {
+    ledger_host: String,
+    ccash_session: Option<CCashSession>,
+    market_user_uid: Option<UserUID>,
+    market_user_details: (String, String),
+    self_trade_prevention: SelfTradePrevention,
+    data: Data,
+    books: Books,
+}
+
+impl AppState {
+    fn get_data() -> Data {
+        let Some(project_dir) = ProjectDirs::from("", "", "ccash-market") else {
+          tracing::error!("Could not find suitable application directory!");
+          std::process::exit(-1);
+        };
+
+        let data_dir = project_dir.data_dir();
+
+        if !data_dir.exists() {
+            if let Err(e) = create_dir_all(data_dir) {
+                tracing::error!("{e}");
+                std::process::exit(-1);
+            }
+        }
+
+        let file = File::options()
+            .write(true)
+            .read(true)
+            .create(true)
+            .open(data_dir.join("data.gz"));
+
+        if let Err(e) = file {
+            tracing::error!("{e}");
+            std::process::exit(-1);
+        }
+        let file = file.unwrap();
+
+        let bufreader = BufReader::new(file);
+        let mut decoder = GzDecoder::new(bufreader);
+        let mut buffer = String::new();
+
+        _ = decoder.read_to_string(&mut buffer);
+
+        let data = serde_json::from_str::<Value>(&buffer).and_then(|mut value| {
+            migrate_data(&mut value);
+            serde_json::from_value::<Data>(value)
+        });
+
+        if let Ok(data) = data {
+            data
+        } else {
+            tracing::warn!(
+                "\"{}\" was empty or could not be read properly, using new data...",
+                data_dir.join("data.gz").to_string_lossy()
+            );
+            Data::default()
+        }
+    }
+
+    fn get_data_dir() -> Result<PathBuf> {
+        let Some(project_dir) = ProjectDirs::from("", "", "ccash-market") else {
+            let message = "Could not find suitable application directory!";
+
+            tracing::error!(message);
+            return Err(Error::msg(message));
+        };
+
+        let data_dir = project_dir.data_dir();
+
+        if !data_dir.exists() {
+            create_dir_all(data_dir)?;
+        }
+
+        Ok(data_dir.to_owned())
+    }
+
+    pub(crate) fn save_data(&self) -> Result<()> {
+        let data_dir = Self::get_data_dir()?;
+        let file_path = data_dir.join("data.gz");
+
+        if file_path.exists() {
+            let old_file = File::open(&file_path)?;
+            let creation_time = DateTime::<Utc>::from(old_file.metadata()?.created()?)
+                .to_rfc3339_opts(SecondsFormat::Secs, false);
+
+            let file_name = format!("data-{creation_time}.gz.bak");
+
+            tracing::info!(
+                "Found previous \"data.gz\", backing up to \"data/{file_name}\"."
+            );
+
+            let backup_dir = data_dir.join("data");
+            create_dir_all(&backup_dir)?;
+
+            rename(&file_path, backup_dir.join(file_name))?;
+        }
+
+        tracing::info!("Writing data to {}...", file_path.to_string_lossy());
+
+        self.write_data()?;
+
+        tracing::info!("... Done!");
+
+        Ok(())
+    }
+
+    /// Writes the data over "data.gz" without backing it up. It is written to
+    /// a temporary file first, so "data.gz" is never left half written.
+    fn write_data(&self) -> Result<()> {
+        let data_dir = Self::get_data_dir()?;
+        let temp_path = data_dir.join("data.gz.tmp");
+
+        let file = File::create(&temp_path)?;
+        let bufwriter = BufWriter::new(file);
+        let mut encoder = GzEncoder::new(bufwriter, Compression::best());
+
+        serde_json::to_writer(&mut encoder, &self.data)?;
+
+        encoder.finish()?.into_inner()?.sync_all()?;
+
+        rename(temp_path, data_dir.join("data.gz"))?;
+
+        Ok(())
+    }
+
+    /// Builds the order book of every commodity from the offers and trades in
+    /// `data`.
+    fn build_books(data: &Data) -> Books {
+        let books = Books::new();
+
+        for kv in &data.offers {
+            let offer = kv.value().read();
+
+            if !offer.get_status().is_final() {
+                books
+                    .entry(offer.get_commodity_id())
+                    .or_default()
+                    .insert(*kv.key(), &offer);
+            }
+        }
+
+        let mut trades = data
+            .trades
+            .iter()
+            .map(|kv| kv.value().read().clone())
+            .filter(|trade| !matches!(trade.get_settlement(), Settlement::Failed { .. }))
+            .collect::<Vec<_>>();
+
+        trades.sort_by_key(Trade::get_datetime);
+
+        for trade in trades {
+            books
+                .entry(trade.get_commodity_id())
+                .or_default()
+                .record_price(
+                    trade.get_datetime(),
+                    trade.get_price_per_item(),
+                    trade.get_item_amount(),
+                );
+        }
+
+        let now = Utc::now();
+
+        for kv in &data.commodities {
+            if let Some(mut book) = books.get_mut(kv.key()) {
+                book.prune_prices(
+                    kv.value()
+                        .read()
+                        .get_circuit_breaker()
+                        .get_window_start(now),
+                );
+            }
+        }
+
+        books
+    }
+
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let ledger_host = if let Some(ledger_host) = config.get_ledger_host() {
+            ledger_host
+        } else {
+            "Unset"
+        };
+
+        let data = Self::get_data();
+        let books = Self::build_books(&data);
+
+        Self {
+            ledger_host: ledger_host.to_owned(),
+            ccash_session: None,
+            market_user_uid: None,
+            market_user_details: (
+                config.get_market_username().to_owned(),
+                config.get_market_password().to_owned(),
+            ),
+            self_trade_prevention: config.get_self_trade_prevention(),
+            data,
+            books,
+        }
+    }
+
+    pub(crate) async fn connect(&mut self) -> Result<()> {
+        let mut ccash_session = CCashSession::new(&self.ledger_host);
+        ccash_session.establish_connection().await?;
+
+        self.ccash_session = Some(ccash_session);
+        self.init_market_user().await?;
+
+        Ok(())
+    }
+
+    async fn init_market_user(&mut self) -> Result<()> {
+        if !self
+            .ccash_session
+            .clone()
+            .is_some_and(|session| session.is_connected())
+        {
+            let message = "CCash session invalid or not connection not established.";
+
+            tracing::error!(message);
+            return Err(Error::msg(message));
+        }
+
+        let session = self.ccash_session.as_ref().unwrap();
+
+        let market_user =
+            CCashUser::new(&self.market_user_details.0, &self.market_user_details.1)?;
+
+        let contains = if let Ok(contains) = m::contains_user(session, &market_user).await
+        {
+            contains
+        } else {
+            false
+        };
+
+        if !contains {
+            tracing::info!(
+                "Market user with the name \"{}\" doesn't exist. Adding...",
+                market_user.get_username()
+            );
+
+            if dbg!(m::add_user(session, &market_user).await)? {
+                tracing::info!(
+                    "User with name \"{}\" added to CCash instance.",
+                    market_user.get_username()
+                );
+            }
+        } else if !m::verify_password(session, &market_user).await? {
+            let message = format!(
+                "Market account \"{}\" has the incorrect password!",
+                market_user.get_username()
+            );
+
+            tracing::error!(message);
+            return Err(Error::msg(message));
+        }
+
+        let market_user_id = if let Some((id, _)) = self
+            .data
+            .users
+            .par_iter()
+            .map(|rw| (*rw.key(), Arc::clone(rw.value())))
+            .find_first(|(_, user)| {
+                user.read().get_username() == market_user.get_username()
+            }) {
+            id
+        } else {
+            UserUID(Uuid::new_v4())
+        };
+
+        tracing::info!(
+            "Market user \"{}\" has UUID of {market_user_id}",
+            market_user.get_username()
+        );
+
+        if !self.data.users.contains_key(&market_user_id) {
+            let market_user =
+                Arc::new(RwLock::new(User::new(market_user.get_username())));
+
+            self.data
+                .users
+                .insert(market_user_id, Arc::clone(&market_user));
+        }
+
+        self.market_user_uid = Some(market_user_id);
+
+        Ok(())
+    }
+
+    fn get_market_user(&self) -> Option<Arc<RwLock<User>>> {
+        let Some(uid) = self.market_user_uid  else {
+            return None;
+        };
+
+        self.data.users.get(&uid).map(|user| Arc::clone(&*user))
+    }
+
+    pub fn get_or_add_user(&mut self, user: &CCashUser) -> UserUID {
+        self.get_or_add_username(user.get_username())
+    }
+
+    /// Finds the user called `username`, adding them if they haven't used the
+    /// market before.
+    pub fn get_or_add_username(&mut self, username: &str) -> UserUID {
+        let existing = self
+            .data
+            .users
+            .iter()
+            .find(|kv| kv.value().read().get_username() == username)
+            .map(|kv| *kv.key());
+
+        if let Some(uuid) = existing {
+            return uuid;
+        }
+
+        let uuid = UserUID(Uuid::new_v4());
+        let user = User::new(username);
+
+        self.data.users.insert(uuid, Arc::new(RwLock::new(user)));
+
+        uuid
+    }
+
+    pub fn get_commodity_id(&self, commodity_name: &str) -> Option<CommodityUID> {
+        self.data
+            .commodities
+            .iter()
+            .find(|kv| kv.value().read().get_name() == commodity_name)
+            .map(|kv| *kv.key())
+    }
+
+    /// Whether matching in `commodity_id` is paused, either because trading
+    /// has been halted or because it is outside of its trading hours.
+    fn is_paused(&self, commodity_id: CommodityUID) -> bool {
+        self.data.commodities.get(&commodity_id).is_some_and(|kv| {
+            let commodity = kv.value().read();
+
+            commodity.is_halted() || commodity.get_session() != TradingSession::Open
+        })
+    }
+
+    fn is_auction(&self, commodity_id: CommodityUID) -> bool {
+        self.data
+            .commodities
+            .get(&commodity_id)
+            .is_some_and(|kv| kv.value().read().is_auction())
+    }
+
+    /// Whether `user` is the market's own account, which administers the
+    /// market.
+    pub(crate) fn is_market_user(&self, user: &CCashUser) -> bool {
+        user.get_username() == self.market_user_details.0
+    }
+
+    /// Switches `commodity_id` between continuous trading and auctions. An
+    /// auctioned commodity's book can be crossed, so switching back to
+    /// continuous trading runs one last auction first.
+    pub fn set_trading_mode(
+        &mut self,
+        commodity_id: CommodityUID,
+        trading_mode: TradingMode,
+    ) -> Result<Matches, StateError> {
+        if trading_mode
+            == (TradingMode::Auction {
+                interval_seconds: 0,
+            })
+        {
+            return Err(StateError::InvalidSettings(
+                "interval_seconds cannot be 0".into(),
+            ));
+        }
+
+        let Some(commodity) = self.data.commodities.get(&commodity_id).map(|kv| Arc::clone(kv.value())) else {
+            return Err(StateError::CommodityNotFound(commodity_id));
+        };
+
+        let now = Utc::now();
+        let matches = if commodity.read().is_auction()
+            && trading_mode == TradingMode::Continuous
+            && !self.is_paused(commodity_id)
+        {
+            self.run_auction(commodity_id, now)
+        } else {
+            Matches::default()
+        };
+
+        commodity.write().set_trading_mode(trading_mode, now);
+
+        tracing::info!("Commodity {commodity_id} switched to {trading_mode:?} trading");
+
+        Ok(matches)
+    }
+
+    /// Replaces the trading rules for `commodity_id`. Offers already placed
+    /// aren't affected.
+    pub fn set_trading_rules(
+        &mut self,
+        commodity_id: CommodityUID,
+        trading_rules: TradingRules,
+    ) -> Result<(), StateError> {
+        trading_rules
+            .validate()
+            .map_err(StateError::InvalidSettings)?;
+
+        let Some(commodity) = self.data.commodities.get(&commodity_id) else {
+            return Err(StateError::CommodityNotFound(commodity_id));
+        };
+
+        commodity.value().write().set_trading_rules(trading_rules);
+
+        tracing::info!("Commodity {commodity_id} trading rules set to {trading_rules:?}");
+
+        Ok(())
+    }
+
+    /// Replaces the circuit breaker settings for `commodity_id`. A halt which
+    /// is already in place keeps its original resume time.
+    pub fn set_circuit_breaker(
+        &mut self,
+        commodity_id: CommodityUID,
+        circuit_breaker: CircuitBreaker,
+    ) -> Result<(), StateError> {
+        circuit_breaker
+            .validate()
+            .map_err(StateError::InvalidSettings)?;
+
+        let Some(commodity) = self.data.commodities.get(&commodity_id) else {
+            return Err(StateError::CommodityNotFound(commodity_id));
+        };
+
+        commodity
+            .value()
+            .write()
+            .set_circuit_breaker(circuit_breaker);
+
+        tracing::info!(
+            "Commodity {commodity_id} circuit breaker set to {circuit_breaker:?}"
+        );
+
+        Ok(())
+    }
+
+    /// Lifts the halt on trading in `commodity_id`. The prices traded before
+    /// the halt are forgotten by the circuit breaker, so that trading doesn't
+    /// halt again straight away. Crossing offers left in the book are matched
+    /// in an auction, and any stop offers triggered in the meantime are
+    /// activated.
+    pub fn resume_trading(
+        &mut self,
+        commodity_id: CommodityUID,
+    ) -> Result<Matches, StateError> {
+        let Some(commodity) = self.data.commodities.get(&commodity_id).map(|kv| Arc::clone(kv.value())) else {
+            return Err(StateError::CommodityNotFound(commodity_id));
+        };
+
+        if !commodity.write().resume() {
+            return Err(StateError::NotHalted(
+                commodity.read().get_name().to_owned(),
+            ));
+        }
+
+        if let Some(mut book) = self.books.get_mut(&commodity_id) {
+            book.clear_recent_prices();
+        }
+
+        tracing::info!("Trading in {commodity_id} resumed");
+
+        if self.is_paused(commodity_id) {
+            return Ok(Matches::default());
+        }
+
+        Ok(self.run_auction(commodity_id, Utc::now()))
+    }
+
+    /// Resumes trading in every commodity whose halt has run its course.
+    pub(crate) fn resume_due_halts(&mut self, now: DateTime<Utc>) -> Matches {
+        let due = self
+            .data
+            .commodities
+            .iter()
+            .filter(|kv| kv.value().read().is_resume_due(now))
+            .map(|kv| *kv.key())
+            .collect::<Vec<_>>();
+
+        let mut matches = Matches::default();
+
+        for commodity_id in due {
+            if let Ok(resumed) = self.resume_trading(commodity_id) {
+                matches.append(resumed);
+            }
+        }
+
+        matches
+    }
+
+    /// Replaces the trading hours of `commodity_id`, or removes them so that
+    /// it is always open.
+    pub fn set_schedule(
+        &mut self,
+        commodity_id: CommodityUID,
+        schedule: Option<TradingSchedule>,
+    ) -> Result<Matches, StateError> {
+        if let Some(schedule) = &schedule {
+            schedule.validate().map_err(StateError::InvalidSettings)?;
+        }
+
+        let Some(commodity) = self.data.commodities.get(&commodity_id) else {
+            return Err(StateError::CommodityNotFound(commodity_id));
+        };
+
+        tracing::info!("Commodity {commodity_id} schedule set to {schedule:?}");
+
+        commodity.value().write().set_schedule(schedule);
+        drop(commodity);
+
+        Ok(self.update_session(commodity_id, Utc::now()))
+    }
+
+    /// Moves `commodity_id` into the session its schedule puts it in at `now`.
+    /// Offers placed during pre-open can cross, so they are matched in an
+    /// opening auction when the commodity opens.
+    fn update_session(
+        &mut self,
+        commodity_id: CommodityUID,
+        now: DateTime<Utc>,
+    ) -> Matches {
+        let Some(commodity) = self.data.commodities.get(&commodity_id).map(|kv| Arc::clone(kv.value())) else {
+            return Matches::default();
+        };
+
+        let opened = commodity.write().update_session(now);
+
+        if !opened {
+            return Matches::default();
+        }
+
+        tracing::info!("Commodity {commodity_id} opened for trading");
+
+        if self.is_paused(commodity_id) {
+            return Matches::default();
+        }
+
+        self.run_auction(commodity_id, now)
+    }
+
+    /// Moves every commodity into the session its schedule puts it in at
+    /// `now`.
+    pub(crate) fn update_sessions(&mut self, now: DateTime<Utc>) -> Matches {
+        let commodity_ids = self
+            .data
+            .commodities
+            .iter()
+            .map(|kv| *kv.key())
+            .collect::<Vec<_>>();
+
+        let mut matches = Matches::default();
+
+        for commodity_id in commodity_ids {
+            matches.append(self.update_session(commodity_id, now));
+        }
+
+        matches
+    }
+
+    /// Issues `amount` new items of the commodity called `commodity_name` to
+    /// `user_id`, creating the commodity if it doesn't exist yet. This is the
+    /// only way items come into the market, and only the market user can do
+    /// it.
+    pub fn issue_commodity(
+        &mut self,
+        commodity_name: &str,
+        user_id: UserUID,
+        amount: u64,
+    ) -> Result<CommodityUID, StateError> {
+        if commodity_name.is_empty() {
+            return Err(StateError::InvalidIssuance(
+                "Commodity name cannot be empty".into(),
+            ));
+        }
+
+        if amount == 0 {
+            return Err(StateError::InvalidIssuance("Cannot issue 0 items".into()));
+        }
+
+        let Some(commodity_id) = self.get_commodity_id(commodity_name) else {
+            let commodity_id = CommodityUID(Uuid::new_v4());
+            let commodity = Commodity::new(commodity_name, Some(amount), Some(user_id));
+
+            self.data
+                .commodities
+                .insert(commodity_id, Arc::new(RwLock::new(commodity)));
+
+            tracing::info!(
+                "Commodity \"{commodity_name}\" created with {amount} item(s) issued to \
+                 {user_id}"
+            );
+
+            return Ok(commodity_id);
+        };
+
+        let issued = self
+            .data
+            .commodities
+            .get(&commodity_id)
+            .is_some_and(|kv| kv.value().write().issue(user_id, amount));
+
+        if !issued {
+            return Err(StateError::InvalidIssuance(format!(
+                "Cannot issue {amount} more \"{commodity_name}\" item(s)"
+            )));
+        }
+
+        tracing::info!("Issued {amount} \"{commodity_name}\" item(s) to {user_id}");
+
+        Ok(commodity_id)
+    }
+
+    /// Adds an ask, locking the items it sells in the seller's holdings.
+    pub fn add_ask(
+        &mut self,
+        commodity_id: CommodityUID,
+        user_id: UserUID,
+        amount: u64,
+        price_per_item: u64,
+        order_type: OrderType,
+        options: OfferOptions,
+    ) -> Result<(OfferUID, Matches), StateError> {
+        let Some(commodity) = self
+            .data
+            .commodities
+            .get(&commodity_id)
+            .map(|kv| Arc::clone(kv.value()))
+        else {
+            return Err(StateError::CommodityNotFound(commodity_id));
+        };
+
+        if commodity.read().get_holding(user_id) == 0 {
+            return Err(StateError::InsufficientHoldings {
+                commodity_name: commodity.read().get_name().to_owned(),
+                item_amount: amount,
+                available: 0,
+            });
+        }
+
+        let offer_id = OfferUID(Uuid::new_v4());
+        let ask = Offer::new_ask(
+            user_id,
+            commodity_id,
+            amount,
+            price_per_item,
+            order_type,
+            options,
+            Utc::now(),
+        );
+
+        self.check_trading_halt(&ask)?;
+        self.check_trading_session(&ask)?;
+        self.check_trading_rules(&ask)?;
+        self.check_price_band(&ask)?;
+        self.check_trading_mode(&ask)?;
+        self.check_post_only(&ask)?;
+
+        let mut commodity = commodity.write();
+
+        if !commodity.reserve(user_id, amount) {
+            return Err(StateError::InsufficientHoldings {
+                commodity_name: commodity.get_name().to_owned(),
+                item_amount: amount,
+                available: commodity.get_available(user_id),
+            });
+        }
+
+        drop(commodity);
+
+        Ok((offer_id, self.place_offer(offer_id, ask)))
+    }
+
+    /// Adds a bid whose funds have already been escrowed. Market bids spend at
+    /// most `max_total_cost`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_bid(
+        &mut self,
+        commodity_id: CommodityUID,
+        user_id: UserUID,
+        amount: u64,
+        price_per_item: u64,
+        order_type: OrderType,
+        options: OfferOptions,
+        max_total_cost: Option<u64>,
+    ) -> Result<(OfferUID, Matches), StateError> {
+        let offer_id = OfferUID(Uuid::new_v4());
+        let bid = Offer::new_bid(
+            user_id,
+            commodity_id,
+            amount,
+            price_per_item,
+            order_type,
+            max_total_cost,
+            options,
+            Utc::now(),
+        );
+
+        self.check_trading_halt(&bid)?;
+        self.check_trading_session(&bid)?;
+        self.check_trading_rules(&bid)?;
+        self.check_price_band(&bid)?;
+        self.check_trading_mode(&bid)?;
+        self.check_post_only(&bid)?;
+
+        Ok((offer_id, self.place_offer(offer_id, bid)))
+    }
+
+    fn get_circuit_breaker(&self, commodity_id: CommodityUID) -> CircuitBreaker {
+        self.data
+            .commodities
+            .get(&commodity_id)
+            .map(|kv| kv.value().read().get_circuit_breaker())
+            .unwrap_or_default()
+    }
+
+    /// Rejects offers while trading in their commodity is halted.
+    fn check_trading_halt(&self, offer: &Offer) -> Result<(), StateError> {
+        let Some(commodity) = self.data.commodities.get(&offer.get_commodity_id()) else {
+            return Ok(());
+        };
+
+        let commodity = commodity.value().read();
+
+        match commodity.get_halt() {
+            Some(halt) => Err(StateError::TradingHalted {
+                commodity_name: commodity.get_name().to_owned(),
+                reason: halt.reason.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Rejects offers while their commodity is closed, and offers which can't
+    /// wait for the opening while it is in pre-open.
+    fn check_trading_session(&self, offer: &Offer) -> Result<(), StateError> {
+        let Some(commodity) = self.data.commodities.get(&offer.get_commodity_id()) else {
+            return Ok(());
+        };
+
+        let commodity = commodity.value().read();
+
+        match commodity.get_session() {
+            TradingSession::Closed =>
+                Err(StateError::TradingClosed(commodity.get_name().to_owned())),
+            TradingSession::PreOpen if !offer.rests() => Err(
+                StateError::PreOpenRestricted(commodity.get_name().to_owned()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// The price which offers for `commodity_id` are compared against by its
+    /// price band. Moving averages fall back to the last trade price if
+    /// nothing has traded within the circuit breaker's window.
+    fn get_reference_price(
+        &self,
+        commodity_id: CommodityUID,
+        circuit_breaker: &CircuitBreaker,
+    ) -> Option<u64> {
+        let book = self.books.get(&commodity_id)?;
+
+        match circuit_breaker.reference_price {
+            ReferencePrice::LastTrade => book.get_last_price(),
+            ReferencePrice::MovingAverage => book
+                .get_average_price(circuit_breaker.get_window_start(Utc::now()))
+                .or_else(|| book.get_last_price()),
+        }
+    }
+
+    /// Rejects limit offers priced outside their commodity's price band.
+    /// Commodities which haven't traded yet have no band.
+    fn check_price_band(&self, offer: &Offer) -> Result<(), StateError> {
+        if offer.get_order_type() != OrderType::Limit {
+            return Ok(());
+        }
+
+        let circuit_breaker = self.get_circuit_breaker(offer.get_commodity_id());
+
+        match self.get_reference_price(offer.get_commodity_id(), &circuit_breaker) {
+            Some(reference) =>
+                circuit_breaker.check_band(offer.get_price_per_item(), reference),
+            None => Ok(()),
+        }
+    }
+
+    fn get_trading_rules(&self, commodity_id: CommodityUID) -> TradingRules {
+        self.data
+            .commodities
+            .get(&commodity_id)
+            .map(|kv| kv.value().read().get_trading_rules())
+            .unwrap_or_default()
+    }
+
+    fn check_trading_rules(&self, offer: &Offer) -> Result<(), StateError> {
+        self.get_trading_rules(offer.get_commodity_id())
+            .check_offer(offer)
+    }
+
+    /// Rejects offers which can't wait for an auction if their commodity is
+    /// auctioned: anything that doesn't rest in the book, and all-or-none
+    /// offers, which auctions don't fill.
+    fn check_trading_mode(&self, offer: &Offer) -> Result<(), StateError> {
+        if self.is_auction(offer.get_commodity_id())
+            && (!offer.rests() || offer.get_options().all_or_none)
+        {
+            return Err(StateError::AuctionRestricted);
+        }
+
+        Ok(())
+    }
+
+    /// Rejects a post-only offer which would trade as soon as it was placed.
+    /// Dormant stop offers are only checked once they are triggered.
+    fn check_post_only(&self, offer: &Offer) -> Result<(), StateError> {
+        if offer.get_options().post_only
+            && offer.get_status() != OfferStatus::Dormant
+            && self.would_cross(offer)
+        {
+            return Err(StateError::PostOnlyWouldTrade);
+        }
+
+        Ok(())
+    }
+
+    /// Matches a new offer against the book and stores it, along with any stop
+    /// offers its trades trigger. Stop offers which haven't been triggered yet
+    /// are stored without matching.
+    fn place_offer(&mut self, offer_id: OfferUID, mut offer: Offer) -> Matches {
+        let commodity_id = offer.get_commodity_id();
+        let last_price = self
+            .books
+            .get(&commodity_id)
+            .and_then(|book| book.get_last_price());
+
+        if offer.get_status() == OfferStatus::Dormant
+            && last_price.is_some_and(|price| offer.is_triggered_by(price))
+        {
+            offer.transition(OfferStatus::Open, Utc::now());
+        }
+
+        let mut matches = if offer.get_status() == OfferStatus::Dormant {
+            self.add_to_book(offer_id, &offer);
+
+            Matches::default()
+        } else {
+            self.execute_offer(offer_id, &mut offer)
+        };
+
+        self.data
+            .offers
+            .insert(offer_id, Arc::new(RwLock::new(offer)));
+
+        matches.append(self.trigger_stops(commodity_id));
+
+        matches
+    }
+
+    /// What it would currently cost `user_id` to buy `amount` items of
+    /// `commodity_id` at the best available prices, or less if there aren't
+    /// enough asks. The user's own asks are skipped, as self-trade prevention
+    /// never lets the bid trade against them.
+    pub fn get_market_buy_cost(
+        &self,
+        commodity_id: CommodityUID,
+        user_id: UserUID,
+        amount: u64,
+    ) -> u64 {
+        let bid = Offer::new_bid(
+            user_id,
+            commodity_id,
+            amount,
+            0,
+            OrderType::Market,
+            None,
+            OfferOptions::default(),
+            Utc::now(),
+        );
+
+        let mut remaining = amount;
+        let mut cost: u64 = 0;
+
+        for (_, ask) in self.crossing_offers(&bid) {
+            if remaining == 0 {
+                break;
+            }
+
+            let ask = ask.read();
+
+            // All-or-none asks can only be bought whole.
+            if ask.get_user_id() == user_id
+                || ask.get_options().all_or_none && ask.get_remaining_amount() > remaining
+            {
+                continue;
+            }
+
+            let item_amount = remaining.min(ask.get_remaining_amount());
+
+            remaining -= item_amount;
+            cost =
+                cost.saturating_add(item_amount.saturating_mul(ask.get_price_per_item()));
+        }
+
+        cost
+    }
+
+    /// Summarises the trades in `commodity_id` since `since`, for its ticker.
+    pub(crate) fn get_trade_stats(
+        &self,
+        commodity_id: CommodityUID,
+        since: DateTime<Utc>,
+    ) -> TradeStats {
+        self.books
+            .get(&commodity_id)
+            .map(|book| book.get_trade_stats(since))
+            .unwrap_or_default()
+    }
+
+    /// Aggregates the best `depth` price levels on each side of
+    /// `commodity_id`'s order book, returning the bids and then the asks. Only
+    /// the visible part of iceberg offers is counted.
+    pub fn get_book_depth(
+        &self,
+        commodity_id: CommodityUID,
+        depth: usize,
+    ) -> (Vec<PriceLevel>, Vec<PriceLevel>) {
+        let Some(book) = self.books.get(&commodity_id) else {
+            return (Vec::new(), Vec::new());
+        };
+
+        let now = Utc::now();
+        let aggregate = |(price_per_item, level): (u64, &Level)| {
+            let mut item_amount: u64 = 0;
+            let mut offer_count = 0;
+
+            for (_, offer_id) in level {
+                let Some(offer) = self.data.offers.get(offer_id).map(|kv| Arc::clone(kv.value())) else {
+                    continue;
+                };
+
+                let offer = offer.read();
+
+                if offer.get_status().is_live() && !offer.has_expired(now) {
+                    item_amount = item_amount.saturating_add(offer.get_visible_amount());
+                    offer_count += 1;
+                }
+            }
+
+            (offer_count > 0).then_some(PriceLevel {
+                price_per_item,
+                item_amount,
+                offer_count,
+            })
+        };
+
+        let bids = book
+            .get_bid_levels()
+            .filter_map(&aggregate)
+            .take(depth)
+            .collect();
+        let asks = book
+            .get_ask_levels()
+            .filter_map(&aggregate)
+            .take(depth)
+            .collect();
+
+        (bids, asks)
+    }
+
+    /// Cancels a single live or dormant offer. Only the user who placed the
+    /// offer may cancel it.
+    pub fn cancel_offer(
+        &mut self,
+        offer_id: OfferUID,
+        user_id: UserUID,
+    ) -> Result<Offer, StateError> {
+        let Some(offer) = self.data.offers.get(&offer_id).map(|kv| Arc::clone(kv.value())) else {
+            return Err(StateError::OfferNotFound(offer_id));
+        };
+
+        let mut offer = offer.write();
+
+        if offer.get_user_id() != user_id {
+            return Err(StateError::NotOfferOwner(offer_id));
+        }
+
+        if !offer.transition(OfferStatus::Cancelled, Utc::now()) {
+            return Err(StateError::OfferClosed(offer_id, offer.get_status()));
+        }
+
+        tracing::info!("Offer {offer_id} cancelled by {user_id}");
+
+        self.remove_from_book(offer_id, &offer);
+        self.release_items(&offer);
+
+        Ok(offer.clone())
+    }
+
+    /// Expires every live or dormant good-till-date offer whose expiry is at or
+    /// before `now`, returning the offers that were expired so that any
+    /// funds they still have escrowed can be refunded.
+    pub fn expire_offers(&mut self, now: DateTime<Utc>) -> Vec<Offer> {
+        let expired = self
+            .data
+            .offers
+            .iter()
+            .filter_map(|kv| {
+                let mut offer = kv.value().write();
+
+                if !offer.has_expired(now) || !offer.transition(OfferStatus::Expired, now)
+                {
+                    return None;
+                }
+
+                tracing::info!("Offer {} expired", kv.key());
+
+                Some((*kv.key(), offer.clone()))
+            })
+            .collect::<Vec<_>>();
+
+        for (offer_id, offer) in &expired {
+            self.remove_from_book(*offer_id, offer);
+            self.release_items(offer);
+        }
+
+        expired.into_iter().map(|(_, offer)| offer).collect()
+    }
+
+    /// Adds a live offer to its commodity's order book.
+    fn add_to_book(&self, offer_id: OfferUID, offer: &Offer) {
+        self.books
+            .entry(offer.get_commodity_id())
+            .or_default()
+            .insert(offer_id, offer);
+    }
+
+    /// Takes an offer out of its commodity's order book once it can no longer
+    /// trade, or before its price changes.
+    fn remove_from_book(&self, offer_id: OfferUID, offer: &Offer) {
+        if let Some(mut book) = self.books.get_mut(&offer.get_commodity_id()) {
+            book.remove(offer_id, offer);
+        }
+    }
+
+    /// Unlocks whatever an ask still has reserved in the seller's holdings once
+    /// it can no longer trade.
+    fn release_items(&self, offer: &Offer) {
+        if let Some(commodity) = self.data.commodities.get(&offer.get_commodity_id()) {
+            commodity
+                .value()
+                .write()
+                .release(offer.get_user_id(), offer.get_reserved_items());
+        }
+    }
+
+    /// Amends the price and/or size of a live offer. Only the user who placed
+    /// the offer may amend it.
+    ///
+    /// Reducing the remaining amount keeps the offer's time priority, while
+    /// changing its price loses it and re-matches the offer against the book.
+    /// The remaining amount can only be reduced, use a new offer to increase
+    /// it.
+    pub fn amend_offer(
+        &mut self,
+        offer_id: OfferUID,
+        user_id: UserUID,
+        price_per_item: Option<u64>,
+        remaining_amount: Option<u64>,
+    ) -> Result<Amendment, StateError> {
+        let Some(offer_lock) = self.data.offers.get(&offer_id).map(|kv| Arc::clone(kv.value())) else {
+            return Err(StateError::OfferNotFound(offer_id));
+        };
+
+        let mut offer = offer_lock.read().clone();
+        let escrow_before = offer.get_escrowed_funds();
+        let items_before = offer.get_reserved_items();
+
+        if offer.get_user_id() != user_id {
+            return Err(StateError::NotOfferOwner(offer_id));
+        }
+
+        if !offer.get_status().is_live() {
+            return Err(StateError::OfferClosed(offer_id, offer.get_status()));
+        }
+
+        if price_per_item == Some(0) {
+            return Err(StateError::InvalidAmendment(
+                "price_per_item cannot be 0".into(),
+            ));
+        }
+
+        let trading_rules = self.get_trading_rules(offer.get_commodity_id());
+
+        if let Some(remaining_amount) = remaining_amount {
+            if remaining_amount == 0 || remaining_amount >= offer.get_remaining_amount() {
+                return Err(StateError::InvalidAmendment(format!(
+                    "Remaining amount can only be reduced (currently {}) and must be \
+                     above 0",
+                    offer.get_remaining_amount()
+                )));
+            }
+
+            trading_rules.check_amount("remaining_amount", remaining_amount)?;
+
+            offer.reduce_remaining(remaining_amount);
+        }
+
+        if offer.get_order_type() == OrderType::Limit {
+            trading_rules.check_value(
+                offer.get_remaining_amount(),
+                price_per_item.unwrap_or(offer.get_price_per_item()),
+            )?;
+        }
+
+        let mut trade_ids = Vec::new();
+        let mut released = Vec::new();
+        let mut escrow_after = offer.get_escrowed_funds();
+        let mut items_after = offer.get_reserved_items();
+
+        if let Some(price_per_item) =
+            price_per_item.filter(|price| *price != offer.get_price_per_item())
+        {
+            trading_rules.check_price("price_per_item", price_per_item)?;
+
+            let mut repriced = offer.clone();
+            repriced.reprice(price_per_item, Utc::now());
+            self.check_trading_halt(&repriced)?;
+            self.check_trading_session(&repriced)?;
+            self.check_price_band(&repriced)?;
+            self.check_post_only(&repriced)?;
+
+            self.remove_from_book(offer_id, &offer);
+            offer.reprice(price_per_item, Utc::now());
+            escrow_after = offer.get_escrowed_funds();
+
+            let matches = self.match_offer(offer_id, &mut offer);
+            trade_ids = matches.trade_ids;
+            released = matches.released;
+
+            if offer.get_status().is_live() {
+                self.add_to_book(offer_id, &offer);
+            } else {
+                // Self-trade prevention may have cancelled the offer, in which
+                // case whatever it had left no longer needs holding.
+                escrow_after = escrow_after.saturating_sub(offer.get_escrowed_funds());
+                items_after = items_after.saturating_sub(offer.get_reserved_items());
+            }
+        }
+
+        tracing::info!("Offer {offer_id} amended by {user_id}");
+
+        if let Some(commodity) = self.data.commodities.get(&offer.get_commodity_id()) {
+            commodity
+                .value()
+                .write()
+                .release(user_id, items_before.saturating_sub(items_after));
+        }
+
+        let commodity_id = offer.get_commodity_id();
+        *offer_lock.write() = offer;
+
+        let triggered = self.trigger_stops(commodity_id);
+        trade_ids.extend(triggered.trade_ids);
+        released.extend(triggered.released);
+
+        Ok(Amendment {
+            trade_ids,
+            released,
+            escrow_before,
+            escrow_after,
+        })
+    }
+
+    /// The most extra CSH which could need escrowing if `offer_id` were amended
+    /// with the given price and remaining amount.
+    pub fn get_escrow_increase(
+        &self,
+        offer_id: OfferUID,
+        price_per_item: Option<u64>,
+        remaining_amount: Option<u64>,
+    ) -> u64 {
+        let Some(offer) = self.data.offers.get(&offer_id).map(|kv| kv.value().read().clone()) else {
+            return 0;
+        };
+
+        if offer.is_ask() {
+            return 0;
+        }
+
+        let remaining = remaining_amount
+            .unwrap_or(u64::MAX)
+            .min(offer.get_remaining_amount());
+        let price = price_per_item.unwrap_or(offer.get_price_per_item());
+
+        remaining
+            .saturating_mul(price)
+            .saturating_sub(offer.get_escrowed_funds())
+    }
+
+    /// Cancels every live or dormant offer belonging to `user_id`, optionally
+    /// only those for `commodity_id`, returning the offers that were
+    /// cancelled.
+    pub fn cancel_offers_for_user(
+        &mut self,
+        user_id: UserUID,
+        commodity_id: Option<CommodityUID>,
+    ) -> Vec<(OfferUID, Offer)> {
+        let Some(offer_ids) = self
+            .data
+            .users
+            .get(&user_id)
+            .map(|kv| kv.value().read().get_offer_ids())
+        else {
+            return Vec::new();
+        };
+
+        let live_offer_ids = offer_ids
+            .into_iter()
+            .filter(|offer_id| {
+                self.data.offers.get(offer_id).is_some_and(|kv| {
+                    let offer = kv.value().read();
+
+                    !offer.get_status().is_final()
+                        && (commodity_id.is_none()
+                            || commodity_id == Some(offer.get_commodity_id()))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        live_offer_ids
+            .into_iter()
+            .filter_map(|offer_id| {
+                self.cancel_offer(offer_id, user_id)
+                    .ok()
+                    .map(|offer| (offer_id, offer))
+            })
+            .collect()
+    }
+
+    pub fn as_properties(&self) -> AppProperties {
+        let market_username = if let Some(market_user) = self.get_market_user() {
+            market_user.read().get_username().to_owned()
+        } else {
+            "Unknown".into()
+        };
+
+        let mut sessions = self
+            .data
+            .commodities
+            .iter()
+            .map(|kv| {
+                let commodity = kv.value().read();
+
+                CommoditySession {
+                    commodity_name: commodity.get_name().to_owned(),
+                    session: commodity.get_session(),
+                    schedule: commodity.get_schedule().cloned(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        sessions.sort_by(|a, b| a.commodity_name.cmp(&b.commodity_name));
+
+        AppProperties {
+            ledger_host: self.ledger_host.clone(),
+            market_username,
+            sessions,
+        }
+    }
+
+    pub(crate) fn get_offers(&self) -> &Offers { &self.data.offers }
+    // pub(crate) fn get_offers_mut(&mut self) -> &mut Offers { &mut
+    // self.data.offers }
+
+    pub(crate) fn get_trades(&self) -> &Trades { &self.data.trades }
+
+    /// Looks up each of `trade_ids`, skipping any which don't exist.
+    pub(crate) fn get_trades_from_ids(&self, trade_ids: &[TradeUID]) -> Vec<Trade> {
+        trade_ids
+            .iter()
+            .filter_map(|id| self.data.trades.get(id))
+            .map(|kv| kv.value().read().clone())
+            .collect()
+    }
+
+    pub(crate) fn get_commodities(&self) -> &Commodities { &self.data.commodities }
+
+    pub(crate) fn get_users(&self) -> &Users { &self.data.users }
+    pub(crate) fn get_users_mut(&mut self) -> &mut Users { &mut self.data.users }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offer::TimeInForce;
+
+    fn empty_state() -> AppState {
+        AppState {
+            ledger_host: String::new(),
+            ccash_session: None,
+            market_user_uid: None,
+            market_user_details: (String::new(), String::new()),
+            self_trade_prevention: SelfTradePrevention::default(),
+            data: Data::default(),
+            books: Books::new(),
+        }
+    }
+
+    #[test]
+    fn amend_past_max_order_value_is_rejected() {
+        let mut state = empty_state();
+        let user_id = state.get_or_add_username("seller");
+        let commodity_id = state.issue_commodity("test", user_id, 10).unwrap();
+
+        state
+            .set_trading_rules(
+                commodity_id,
+                TradingRules {
+                    max_order_value: Some(100),
+                    ..TradingRules::default()
+                },
+            )
+            .unwrap();
+
+        let (ask_id, _) = state
+            .add_ask(
+                commodity_id,
+                user_id,
+                10,
+                5,
+                OrderType::Limit,
+                OfferOptions::default(),
+            )
+            .unwrap();
+
+        assert!(matches!(
+            state.amend_offer(ask_id, user_id, Some(1000), None),
+            Err(StateError::OrderValue(_))
+        ));
+
+        let offer = state
+            .get_offers()
+            .get(&ask_id)
+            .unwrap()
+            .value()
+            .read()
+            .clone();
+        assert_eq!(offer.get_price_per_item(), 5);
+        assert_eq!(offer.get_remaining_amount(), 10);
+    }
+
+    /// A market for a single commodity, with 1000 items held by a seller and a
+    /// buyer with none.
+    fn market() -> (AppState, CommodityUID, UserUID, UserUID) {
+        let mut state = empty_state();
+        let seller_id = state.get_or_add_username("seller");
+        let buyer_id = state.get_or_add_username("buyer");
+        let commodity_id = state.issue_commodity("test", seller_id, 1000).unwrap();
+
+        (state, commodity_id, seller_id, buyer_id)
+    }
+
+    fn limit(display_amount: Option<u64>) -> (OrderType, OfferOptions) {
+        (
+            OrderType::Limit,
+            OfferOptions {
+                display_amount,
+                ..OfferOptions::default()
+            },
+        )
+    }
+
+    fn get_offer(state: &AppState, offer_id: OfferUID) -> Offer {
+        state
+            .get_offers()
+            .get(&offer_id)
+            .unwrap()
+            .value()
+            .read()
+            .clone()
+    }
+
+    fn trade_amounts(state: &AppState, trade_ids: &[TradeUID]) -> Vec<(u64, u64)> {
+        trade_ids
+            .iter()
+            .map(|trade_id| {
+                let trade = state.data.trades.get(trade_id).unwrap();
+                let trade = trade.value().read();
+
+                (trade.get_item_amount(), trade.get_price_per_item())
+            })
+            .collect()
+    }
+
+    /// A commodity with 100 items held by a seller, traded under `rules`.
+    fn ruled_commodity(
+        state: &mut AppState,
+        rules: TradingRules,
+    ) -> (CommodityUID, UserUID) {
+        let user_id = state.get_or_add_username("seller");
+        let commodity_id = state.issue_commodity("test", user_id, 100).unwrap();
+
+        state.set_trading_rules(commodity_id, rules).unwrap();
+
+        (commodity_id, user_id)
+    }
+
+    #[test]
+    fn placing_off_tick_price_is_rejected() {
+        let mut state = empty_state();
+        let (commodity_id, user_id) = ruled_commodity(
+            &mut state,
+            TradingRules {
+                tick_size: 5,
+                ..TradingRules::default()
+            },
+        );
+
+        let result = state.add_ask(
+            commodity_id,
+            user_id,
+            10,
+            7,
+            OrderType::Limit,
+            OfferOptions::default(),
+        );
+
+        assert!(matches!(result, Err(StateError::TickSize(_))));
+        assert!(state.get_offers().is_empty());
+    }
+
+    #[test]
+    fn placing_off_lot_amount_is_rejected() {
+        let mut state = empty_state();
+        let (commodity_id, user_id) = ruled_commodity(
+            &mut state,
+            TradingRules {
+                lot_size: 10,
+                ..TradingRules::default()
+            },
+        );
+
+        let result = state.add_bid(
+            commodity_id,
+            user_id,
+            15,
+            5,
+            OrderType::Limit,
+            OfferOptions::default(),
+            None,
+        );
+
+        assert!(matches!(result, Err(StateError::LotSize(_))));
+        assert!(state.get_offers().is_empty());
+    }
+
+    #[test]
+    fn placing_below_min_order_value_is_rejected() {
+        let mut state = empty_state();
+        let (commodity_id, user_id) = ruled_commodity(
+            &mut state,
+            TradingRules {
+                min_order_value: Some(100),
+                ..TradingRules::default()
+            },
+        );
+
+        let result = state.add_ask(
+            commodity_id,
+            user_id,
+            10,
+            5,
+            OrderType::Limit,
+            OfferOptions::default(),
+        );
+
+        assert!(matches!(result, Err(StateError::OrderValue(_))));
+        assert!(state.get_offers().is_empty());
+    }
+
+    #[test]
+    fn resuming_missing_commodity_is_not_found() {
+        let mut state = empty_state();
+        let commodity_id = CommodityUID(Uuid::new_v4());
+
+        assert_eq!(
+            state.resume_trading(commodity_id).err(),
+            Some(StateError::CommodityNotFound(commodity_id))
+        );
+    }
+
+    #[test]
+    fn incoming_iceberg_shows_its_reserve_before_resting() {
+        let (mut state, commodity_id, seller_id, buyer_id) = market();
+        let (order_type, options) = limit(None);
+
+        state
+            .add_ask(commodity_id, seller_id, 15, 10, order_type, options)
+            .unwrap();
+
+        let (order_type, options) = limit(Some(10));
+        let (bid_id, matches) = state
+            .add_bid(commodity_id, buyer_id, 100, 10, order_type, options, None)
+            .unwrap();
+
+        assert_eq!(trade_amounts(&state, &matches.trade_ids), vec![(15, 10)]);
+
+        let bid = get_offer(&state, bid_id);
+        assert_eq!(bid.get_remaining_amount(), 85);
+        assert_eq!(bid.get_visible_amount(), 10);
+
+        let (bids, _) = state.get_book_depth(commodity_id, 10);
+        assert_eq!(bids[0].item_amount, 10);
+
+        let (order_type, options) = limit(None);
+        let (_, matches) = state
+            .add_ask(commodity_id, seller_id, 5, 10, order_type, options)
+            .unwrap();
+
+        assert_eq!(trade_amounts(&state, &matches.trade_ids), vec![(5, 10)]);
+    }
+
+    #[test]
+    fn all_or_none_ask_too_big_to_fill_is_passed_over() {
+        let (mut state, commodity_id, seller_id, buyer_id) = market();
+        let (order_type, options) = limit(None);
+
+        state
+            .add_ask(
+                commodity_id,
+                seller_id,
+                20,
+                5,
+                order_type,
+                OfferOptions {
+                    all_or_none: true,
+                    ..options
+                },
+            )
+            .unwrap();
+        state
+            .add_ask(commodity_id, seller_id, 10, 6, order_type, options)
+            .unwrap();
+
+        assert_eq!(state.get_market_buy_cost(commodity_id, buyer_id, 10), 60);
+
+        let (_, matches) = state
+            .add_bid(
+                commodity_id,
+                buyer_id,
+                10,
+                6,
+                order_type,
+                OfferOptions {
+                    time_in_force: TimeInForce::FillOrKill,
+                    ..options
+                },
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(trade_amounts(&state, &matches.trade_ids), vec![(10, 6)]);
+    }
+
+    #[test]
+    fn offers_saved_without_remaining_amount_keep_their_items() {
+        let offer_id = OfferUID(Uuid::new_v4());
+        let mut value = serde_json::json!({
+            "commodities": {},
+            "offers": {
+                offer_id.0.to_string(): {
+                    "type": "ask",
+                    "user_id": Uuid::new_v4(),
+                    "commodity_id": Uuid::new_v4(),
+                    "datetime": Utc::now(),
+                    "item_amount": 25,
+                    "price_per_item": 4,
+                },
+            },
+            "users": {},
+        });
+
+        migrate_data(&mut value);
+
+        let data = serde_json::from_value::<Data>(value).unwrap();
+        let offer = data.offers.get(&offer_id).unwrap().value().read().clone();
+
+        assert_eq!(offer.get_remaining_amount(), 25);
+        assert_eq!(offer.get_status(), OfferStatus::Open);
+    }
+
+    /// Switches the market's commodity to auctions, which only run when
+    /// `run_auction` is called.
+    fn auction(state: &mut AppState, commodity_id: CommodityUID) {
+        state
+            .set_trading_mode(
+                commodity_id,
+                TradingMode::Auction {
+                    interval_seconds: 3600,
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn auction_cancels_self_matches_by_policy() {
+        let (mut state, commodity_id, seller_id, buyer_id) = market();
+        let (order_type, options) = limit(None);
+
+        auction(&mut state, commodity_id);
+
+        let (ask_id, _) = state
+            .add_ask(commodity_id, seller_id, 10, 5, order_type, options)
+            .unwrap();
+        let (own_bid_id, _) = state
+            .add_bid(commodity_id, seller_id, 10, 5, order_type, options, None)
+            .unwrap();
+        let (bid_id, _) = state
+            .add_bid(commodity_id, buyer_id, 10, 5, order_type, options, None)
+            .unwrap();
+
+        let matches = state.run_auction(commodity_id, Utc::now());
+
+        assert_eq!(trade_amounts(&state, &matches.trade_ids), vec![(10, 5)]);
+        assert_eq!(
+            get_offer(&state, own_bid_id).get_status(),
+            OfferStatus::Cancelled
+        );
+        assert_eq!(matches.released.len(), 1);
+        assert_eq!(get_offer(&state, ask_id).get_status(), OfferStatus::Filled);
+        assert_eq!(get_offer(&state, bid_id).get_status(), OfferStatus::Filled);
+    }
+
+    #[test]
+    fn auction_stops_once_trading_halts() {
+        let (mut state, commodity_id, seller_id, buyer_id) = market();
+        let other_id = state.get_or_add_username("other");
+        let (order_type, options) = limit(None);
+
+        state
+            .set_circuit_breaker(
+                commodity_id,
+                CircuitBreaker {
+                    halt_move_percent: Some(50),
+                    ..CircuitBreaker::default()
+                },
+            )
+            .unwrap();
+        state
+            .add_ask(commodity_id, seller_id, 1, 10, order_type, options)
+            .unwrap();
+        state
+            .add_bid(commodity_id, buyer_id, 1, 10, order_type, options, None)
+            .unwrap();
+
+        auction(&mut state, commodity_id);
+
+        state
+            .add_ask(commodity_id, seller_id, 10, 20, order_type, options)
+            .unwrap();
+        state
+            .add_bid(commodity_id, buyer_id, 5, 20, order_type, options, None)
+            .unwrap();
+        let (other_bid_id, _) = state
+            .add_bid(commodity_id, other_id, 5, 20, order_type, options, None)
+            .unwrap();
+
+        let matches = state.run_auction(commodity_id, Utc::now());
+
+        assert_eq!(trade_amounts(&state, &matches.trade_ids), vec![(5, 20)]);
+        assert!(state.is_paused(commodity_id));
+        assert_eq!(get_offer(&state, other_bid_id).get_remaining_amount(), 5);
+    }
+
+    #[test]
+    fn offers_match_by_price_then_time() {
+        let (mut state, commodity_id, seller_id, buyer_id) = market();
+        let (order_type, options) = limit(None);
+
+        let (first_id, _) = state
+            .add_ask(commodity_id, seller_id, 5, 10, order_type, options)
+            .unwrap();
+        let (second_id, _) = state
+            .add_ask(commodity_id, seller_id, 5, 10, order_type, options)
+            .unwrap();
+        let (best_id, _) = state
+            .add_ask(commodity_id, seller_id, 5, 9, order_type, options)
+            .unwrap();
+
+        let (_, matches) = state
+            .add_bid(commodity_id, buyer_id, 12, 11, order_type, options, None)
+            .unwrap();
+
+        assert_eq!(
+            trade_amounts(&state, &matches.trade_ids),
+            vec![(5, 9), (5, 10), (2, 10)]
+        );
+        assert_eq!(get_offer(&state, best_id).get_status(), OfferStatus::Filled);
+        assert_eq!(
+            get_offer(&state, first_id).get_status(),
+            OfferStatus::Filled
+        );
+        assert_eq!(get_offer(&state, second_id).get_remaining_amount(), 3);
+    }
+
+    #[test]
+    fn partially_filled_offer_rests_in_the_book() {
+        let (mut state, commodity_id, seller_id, buyer_id) = market();
+        let (order_type, options) = limit(None);
+
+        state
+            .add_ask(commodity_id, seller_id, 4, 5, order_type, options)
+            .unwrap();
+
+        let (bid_id, matches) = state
+            .add_bid(commodity_id, buyer_id, 10, 5, order_type, options, None)
+            .unwrap();
+
+        assert_eq!(trade_amounts(&state, &matches.trade_ids), vec![(4, 5)]);
+
+        let bid = get_offer(&state, bid_id);
+        assert_eq!(bid.get_status(), OfferStatus::PartiallyFilled);
+        assert_eq!(bid.get_remaining_amount(), 6);
+
+        let (bids, asks) = state.get_book_depth(commodity_id, 10);
+        assert_eq!(bids[0].item_amount, 6);
+        assert!(asks.is_empty());
+    }
+
+    #[test]
+    fn self_trade_cancels_newest_offer() {
+        let (mut state, commodity_id, seller_id, _) = market();
+        let (order_type, options) = limit(None);
+
+        let (ask_id, _) = state
+            .add_ask(commodity_id, seller_id, 5, 5, order_type, options)
+            .unwrap();
+        let (bid_id, matches) = state
+            .add_bid(commodity_id, seller_id, 5, 5, order_type, options, None)
+            .unwrap();
+
+        assert!(matches.trade_ids.is_empty());
+        assert_eq!(
+            get_offer(&state, bid_id).get_status(),
+            OfferStatus::Cancelled
+        );
+        assert_eq!(get_offer(&state, ask_id).get_status(), OfferStatus::Open);
+    }
+
+    #[test]
+    fn self_trade_cancels_oldest_offer() {
+        let (mut state, commodity_id, seller_id, _) = market();
+        let (order_type, options) = limit(None);
+
+        state.self_trade_prevention = SelfTradePrevention::CancelOldest;
+
+        let (ask_id, _) = state
+            .add_ask(commodity_id, seller_id, 5, 5, order_type, options)
+            .unwrap();
+        let (bid_id, matches) = state
+            .add_bid(commodity_id, seller_id, 5, 5, order_type, options, None)
+            .unwrap();
+
+        assert!(matches.trade_ids.is_empty());
+        assert_eq!(matches.released.len(), 1);
+        assert_eq!(
+            get_offer(&state, ask_id).get_status(),
+            OfferStatus::Cancelled
+        );
+        assert_eq!(get_offer(&state, bid_id).get_status(), OfferStatus::Open);
+    }
+
+    #[test]
+    fn trade_at_stop_price_triggers_stop_offer() {
+        let (mut state, commodity_id, seller_id, buyer_id) = market();
+        let other_id = state.get_or_add_username("other");
+        let (order_type, options) = limit(None);
+
+        state
+            .add_ask(commodity_id, seller_id, 5, 15, order_type, options)
+            .unwrap();
+
+        let (stop_id, matches) = state
+            .add_bid(
+                commodity_id,
+                buyer_id,
+                5,
+                20,
+                order_type,
+                OfferOptions {
+                    stop_price: Some(10),
+                    ..options
+                },
+                None,
+            )
+            .unwrap();
+
+        assert!(matches.trade_ids.is_empty());
+        assert_eq!(
+            get_offer(&state, stop_id).get_status(),
+            OfferStatus::Dormant
+        );
+
+        state
+            .add_bid(commodity_id, other_id, 1, 10, order_type, options, None)
+            .unwrap();
+        let (_, matches) = state
+            .add_ask(commodity_id, seller_id, 1, 10, order_type, options)
+            .unwrap();
+
+        assert_eq!(
+            trade_amounts(&state, &matches.trade_ids),
+            vec![(1, 10), (5, 15)]
+        );
+        assert_eq!(get_offer(&state, stop_id).get_status(), OfferStatus::Filled);
+    }
+
+    #[test]
+    fn resting_iceberg_replenishes_behind_later_offers() {
+        let (mut state, commodity_id, seller_id, buyer_id) = market();
+        let other_id = state.get_or_add_username("other");
+        let (order_type, options) = limit(None);
+
+        let (iceberg_id, _) = state
+            .add_bid(
+                commodity_id,
+                buyer_id,
+                20,
+                10,
+                order_type,
+                limit(Some(5)).1,
+                None,
+            )
+            .unwrap();
+        let (other_bid_id, _) = state
+            .add_bid(commodity_id, other_id, 5, 10, order_type, options, None)
+            .unwrap();
+
+        let (bids, _) = state.get_book_depth(commodity_id, 10);
+        assert_eq!(bids[0].item_amount, 10);
+
+        let (_, matches) = state
+            .add_ask(commodity_id, seller_id, 7, 10, order_type, options)
+            .unwrap();
+
+        assert_eq!(
+            trade_amounts(&state, &matches.trade_ids),
+            vec![(5, 10), (2, 10)]
+        );
+
+        let iceberg = get_offer(&state, iceberg_id);
+        assert_eq!(iceberg.get_remaining_amount(), 15);
+        assert_eq!(iceberg.get_visible_amount(), 5);
+        assert_eq!(get_offer(&state, other_bid_id).get_remaining_amount(), 3);
+    }
+
+    #[test]
+    fn all_or_none_offers_only_trade_whole() {
+        let (mut state, commodity_id, seller_id, buyer_id) = market();
+        let (order_type, options) = limit(None);
+        let all_or_none = OfferOptions {
+            all_or_none: true,
+            ..options
+        };
+
+        state
+            .add_ask(commodity_id, seller_id, 10, 5, order_type, all_or_none)
+            .unwrap();
+
+        let (bid_id, matches) = state
+            .add_bid(commodity_id, buyer_id, 5, 5, order_type, options, None)
+            .unwrap();
+
+        assert!(matches.trade_ids.is_empty());
+        assert_eq!(get_offer(&state, bid_id).get_status(), OfferStatus::Open);
+
+        let (_, matches) = state
+            .add_bid(commodity_id, buyer_id, 10, 5, order_type, all_or_none, None)
+            .unwrap();
+
+        assert_eq!(trade_amounts(&state, &matches.trade_ids), vec![(10, 5)]);
+    }
+
+    #[test]
+    fn fill_or_kill_offer_which_cannot_fill_is_cancelled() {
+        let (mut state, commodity_id, seller_id, buyer_id) = market();
+        let (order_type, options) = limit(None);
+
+        let (ask_id, _) = state
+            .add_ask(commodity_id, seller_id, 5, 5, order_type, options)
+            .unwrap();
+
+        let (bid_id, matches) = state
+            .add_bid(
+                commodity_id,
+                buyer_id,
+                10,
+                5,
+                order_type,
+                OfferOptions {
+                    time_in_force: TimeInForce::FillOrKill,
+                    ..options
+                },
+                None,
+            )
+            .unwrap();
+
+        assert!(matches.trade_ids.is_empty());
+        assert_eq!(matches.released.len(), 1);
+        assert_eq!(
+            get_offer(&state, bid_id).get_status(),
+            OfferStatus::Cancelled
+        );
+        assert_eq!(get_offer(&state, ask_id).get_remaining_amount(), 5);
+    }
+
+    #[test]
+    fn immediate_or_cancel_offer_cancels_what_is_left() {
+        let (mut state, commodity_id, seller_id, buyer_id) = market();
+        let (order_type, options) = limit(None);
+
+        state
+            .add_ask(commodity_id, seller_id, 5, 5, order_type, options)
+            .unwrap();
+
+        let (bid_id, matches) = state
+            .add_bid(
+                commodity_id,
+                buyer_id,
+                10,
+                5,
+                order_type,
+                OfferOptions {
+                    time_in_force: TimeInForce::ImmediateOrCancel,
+                    ..options
+                },
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(trade_amounts(&state, &matches.trade_ids), vec![(5, 5)]);
+        assert_eq!(matches.released.len(), 1);
+
+        let bid = get_offer(&state, bid_id);
+        assert_eq!(bid.get_status(), OfferStatus::Cancelled);
+        assert_eq!(bid.get_remaining_amount(), 5);
+
+        let (bids, _) = state.get_book_depth(commodity_id, 10);
+        assert!(bids.is_empty());
+    }
+
+    #[test]
+    fn auction_clears_at_the_price_trading_the_most_items() {
+        let (mut state, commodity_id, seller_id, buyer_id) = market();
+        let (order_type, options) = limit(None);
+
+        auction(&mut state, commodity_id);
+
+        state
+            .add_ask(commodity_id, seller_id, 4, 8, order_type, options)
+            .unwrap();
+        state
+            .add_ask(commodity_id, seller_id, 6, 9, order_type, options)
+            .unwrap();
+        state
+            .add_bid(commodity_id, buyer_id, 5, 10, order_type, options, None)
+            .unwrap();
+        let (_, matches) = state
+            .add_bid(commodity_id, buyer_id, 5, 9, order_type, options, None)
+            .unwrap();
+
+        assert!(matches.trade_ids.is_empty());
+
+        let matches = state.run_auction(commodity_id, Utc::now());
+
+        assert_eq!(
+            trade_amounts(&state, &matches.trade_ids),
+            vec![(4, 9), (1, 9), (5, 9)]
+        );
+
+        let (bids, asks) = state.get_book_depth(commodity_id, 10);
+        assert!(bids.is_empty());
+        assert!(asks.is_empty());
+    }
+}
@@ -0,0 +1,485 @@
+use super::{AppState, GState};
+use crate::{
+    offer::Offer,
+    trade::{Settlement, Trade, TradeUID},
+    user::UserUID,
+};
+use ccash_rs::{methods as m, CCashSession, CCashUser};
+use chrono::Utc;
+use std::sync::Arc;
+
+/// The payments which have to be made from the market account to settle a
+/// trade: the seller is paid for the items, and the buyer gets back whatever
+/// they escrowed above the price the trade happened at.
+struct Transfer {
+    seller_username: String,
+    payment: u64,
+    buyer_username: String,
+    refund: u64,
+}
+
+impl AppState {
+    fn get_username(&self, user_id: UserUID) -> Option<String> {
+        self.data
+            .users
+            .get(&user_id)
+            .map(|kv| kv.value().read().get_username().to_owned())
+    }
+
+    /// The session and market account used to move funds on the ledger.
+    fn get_ledger(&self) -> Result<(CCashSession, CCashUser), String> {
+        let Some(session) = self.ccash_session.clone() else {
+            return Err("Not connected to CCash".into());
+        };
+
+        let (username, password) = &self.market_user_details;
+        let market_user =
+            CCashUser::new(username, password).map_err(|e| e.to_string())?;
+
+        Ok((session, market_user))
+    }
+
+    /// Every trade matching `filter`, earliest first.
+    fn get_trade_ids_where(&self, filter: impl Fn(&Trade) -> bool) -> Vec<TradeUID> {
+        let mut trade_ids = self
+            .data
+            .trades
+            .iter()
+            .filter_map(|kv| {
+                let trade = kv.value().read();
+
+                filter(&trade).then(|| (trade.get_datetime(), *kv.key()))
+            })
+            .collect::<Vec<_>>();
+
+        trade_ids.sort_by_key(|(datetime, _)| *datetime);
+
+        trade_ids
+            .into_iter()
+            .map(|(_, trade_id)| trade_id)
+            .collect()
+    }
+
+    /// Marks `trade_id` as being paid for and saves the data before any of the
+    /// payment is made. If the market stops before the payment is recorded, the
+    /// trade is then left in flight instead of pending, and isn't paid for
+    /// again.
+    fn begin_settlement(&self, trade_id: TradeUID) -> Result<(), String> {
+        let Some(trade) = self.data.trades.get(&trade_id).map(|kv| Arc::clone(kv.value())) else {
+            return Err(format!("Trade {trade_id} not found"));
+        };
+
+        trade.write().set_settlement(Settlement::InFlight {
+            datetime: Utc::now(),
+        });
+
+        if let Err(e) = self.write_data() {
+            trade.write().set_settlement(Settlement::Pending);
+
+            return Err(format!(
+                "Could not save the trade before paying for it: {e}"
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn set_refund_owed(&self, trade_id: TradeUID, amount: u64) {
+        if let Some(trade) = self.data.trades.get(&trade_id) {
+            trade.value().write().set_refund_owed(amount);
+        }
+    }
+
+    /// Clears the refund still owed for `trade_id` and saves the data before it
+    /// is paid, so that it is never paid twice. Returns the buyer and the
+    /// amount owed to them.
+    fn take_refund_owed(&self, trade_id: TradeUID) -> Result<(UserUID, u64), String> {
+        let Some(trade) = self.data.trades.get(&trade_id).map(|kv| Arc::clone(kv.value())) else {
+            return Err(format!("Trade {trade_id} not found"));
+        };
+
+        let (buyer_id, amount) = {
+            let mut trade = trade.write();
+            let owed = (trade.get_buyer_id(), trade.get_refund_owed());
+
+            trade.set_refund_owed(0);
+
+            owed
+        };
+
+        if let Err(e) = self.write_data() {
+            trade.write().set_refund_owed(amount);
+
+            return Err(format!("Could not save the trade before refunding it: {e}"));
+        }
+
+        Ok((buyer_id, amount))
+    }
+
+    /// Works out the ledger payments needed to settle `trade_id`. Returns
+    /// `None` if the trade doesn't exist or is no longer pending.
+    fn get_transfer(&self, trade_id: TradeUID) -> Option<Result<Transfer, String>> {
+        let trade = self.data.trades.get(&trade_id)?.value().read().clone();
+
+        if *trade.get_settlement() != Settlement::Pending {
+            return None;
+        }
+
+        let Some(seller_username) = self.get_username(trade.get_seller_id()) else {
+            return Some(Err(format!("Seller {} not found", trade.get_seller_id())));
+        };
+
+        let Some(buyer_username) = self.get_username(trade.get_buyer_id()) else {
+            return Some(Err(format!("Buyer {} not found", trade.get_buyer_id())));
+        };
+
+        let has_items = self
+            .data
+            .commodities
+            .get(&trade.get_commodity_id())
+            .is_some_and(|kv| {
+                kv.value().read().get_holding(trade.get_seller_id())
+                    >= trade.get_item_amount()
+            });
+
+        if !has_items {
+            return Some(Err(format!(
+                "Seller {} does not hold enough items",
+                trade.get_seller_id()
+            )));
+        }
+
+        let payment = trade
+            .get_item_amount()
+            .checked_mul(trade.get_price_per_item());
+        let refund = trade
+            .get_item_amount()
+            .checked_mul(trade.get_price_improvement());
+
+        let Some((payment, refund)) = payment.zip(refund) else {
+            return Some(Err("Trade total is too large to transfer".into()));
+        };
+
+        Some(Ok(Transfer {
+            seller_username,
+            payment,
+            buyer_username,
+            refund,
+        }))
+    }
+
+    /// Marks `trade_id` as settled and moves its items from the seller to the
+    /// buyer, or marks it as failed and reverts the fills it made on both of
+    /// its offers so that the book only reflects paid-for trades.
+    ///
+    /// Reverted items and funds go back to offers which are still live. For
+    /// offers which can no longer trade they are released instead, and the
+    /// buyer's refund is returned.
+    fn finish_settlement(
+        &mut self,
+        trade_id: TradeUID,
+        result: Result<(), String>,
+    ) -> Option<(UserUID, u64)> {
+        let Some(trade) = self.data.trades.get(&trade_id).map(|kv| kv.value().clone()) else {
+            return None;
+        };
+
+        let mut trade = trade.write();
+        let datetime = Utc::now();
+
+        match result {
+            Ok(()) => {
+                tracing::info!("Trade {trade_id} settled");
+
+                let transferred = self
+                    .data
+                    .commodities
+                    .get(&trade.get_commodity_id())
+                    .is_some_and(|kv| {
+                        kv.value().write().transfer(
+                            trade.get_seller_id(),
+                            trade.get_buyer_id(),
+                            trade.get_item_amount(),
+                        )
+                    });
+
+                if !transferred {
+                    tracing::error!(
+                        "Trade {trade_id} was paid for but its items could not be \
+                         transferred"
+                    );
+                }
+
+                trade.set_settlement(Settlement::Settled { datetime });
+
+                None
+            },
+            Err(reason) => {
+                tracing::warn!("Trade {trade_id} could not be settled: {reason}");
+
+                let is_live = |offer_id| {
+                    self.data.offers.get(&offer_id).is_some_and(|kv| {
+                        let mut offer = kv.value().write();
+                        offer.revert_fill(trade_id, datetime);
+
+                        // Offers reopened by the revert go back into the book,
+                        // those still in it keep their place.
+                        let is_live = offer.get_status().is_live();
+                        if is_live {
+                            self.add_to_book(offer_id, &offer);
+                        }
+
+                        is_live
+                    })
+                };
+
+                if !is_live(trade.get_ask_id()) {
+                    if let Some(commodity) =
+                        self.data.commodities.get(&trade.get_commodity_id())
+                    {
+                        commodity
+                            .value()
+                            .write()
+                            .release(trade.get_seller_id(), trade.get_item_amount());
+                    }
+                }
+
+                let refund = (!is_live(trade.get_bid_id())).then(|| {
+                    let price =
+                        trade.get_price_per_item() + trade.get_price_improvement();
+
+                    (
+                        trade.get_buyer_id(),
+                        trade.get_item_amount().saturating_mul(price),
+                    )
+                });
+
+                if let Some(mut book) = self.books.get_mut(&trade.get_commodity_id()) {
+                    book.forget_trade(
+                        trade.get_datetime(),
+                        trade.get_price_per_item(),
+                        trade.get_item_amount(),
+                    );
+                }
+
+                trade.set_settlement(Settlement::Failed { reason, datetime });
+
+                refund
+            },
+        }
+    }
+}
+
+async fn send_funds(
+    session: &CCashSession,
+    from: &CCashUser,
+    to_username: &str,
+    amount: u64,
+) -> Result<(), String> {
+    if amount == 0 {
+        return Ok(());
+    }
+
+    let Ok(amount) = u32::try_from(amount) else {
+        return Err(format!("{amount} CSH is too large to transfer"));
+    };
+
+    m::send_funds(session, from, to_username, amount)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Moves `amount` CSH from `payer` into the market account, where it is held
+/// until the bid it pays for is either filled or withdrawn.
+pub(crate) async fn escrow_funds(
+    state: &GState,
+    payer: &CCashUser,
+    amount: u64,
+) -> Result<(), String> {
+    if amount == 0 {
+        return Ok(());
+    }
+
+    let (session, market_user) = state.read().get_ledger()?;
+
+    send_funds(&session, payer, market_user.get_username(), amount).await
+}
+
+async fn send_refund(
+    state: &GState,
+    user_id: UserUID,
+    amount: u64,
+) -> Result<(), String> {
+    let (ledger, username) = {
+        let state = state.read();
+
+        (state.get_ledger(), state.get_username(user_id))
+    };
+
+    match (ledger, username) {
+        (Err(e), _) => Err(e),
+        (_, None) => Err(format!("User {user_id} not found")),
+        (Ok((session, market_user)), Some(username)) =>
+            send_funds(&session, &market_user, &username, amount).await,
+    }
+}
+
+/// Returns `amount` escrowed CSH from the market account to `user_id`.
+/// Failures are logged, as there is nobody left to report them to.
+pub(crate) async fn refund_escrow(state: &GState, user_id: UserUID, amount: u64) {
+    if amount == 0 {
+        return;
+    }
+
+    match send_refund(state, user_id, amount).await {
+        Ok(()) => tracing::info!("Refunded {amount} escrowed CSH to {user_id}"),
+        Err(e) => {
+            tracing::error!("Could not refund {amount} escrowed CSH to {user_id}: {e}");
+        },
+    }
+}
+
+/// Refunds whatever is still escrowed for `offer` once it can no longer trade.
+pub(crate) async fn release_offer(state: &GState, offer: &Offer) {
+    refund_escrow(state, offer.get_user_id(), offer.get_escrowed_funds()).await;
+}
+
+/// Pays the seller of each of `trade_ids` from the buyer's escrowed funds on
+/// the ledger. Price improvement which can't be refunded to the buyer is
+/// recorded on the trade, to be refunded when the market next starts.
+///
+/// The state is only locked between ledger requests, never while waiting on
+/// them.
+pub(crate) async fn settle_trades(state: &GState, trade_ids: &[TradeUID]) {
+    for trade_id in trade_ids {
+        let (ledger, transfer) = {
+            let state = state.read();
+
+            (state.get_ledger(), state.get_transfer(*trade_id))
+        };
+
+        let result = match (transfer, ledger) {
+            (None, _) => continue,
+            (Some(Err(e)), _) | (_, Err(e)) => Err(e),
+            (Some(Ok(transfer)), Ok((session, market_user))) => {
+                // Holding the write lock keeps the background save from
+                // writing the data at the same time.
+                let begun = state.write().begin_settlement(*trade_id);
+
+                let result = match begun {
+                    Ok(()) =>
+                        send_funds(
+                            &session,
+                            &market_user,
+                            &transfer.seller_username,
+                            transfer.payment,
+                        )
+                        .await,
+                    Err(e) => Err(e),
+                };
+
+                if result.is_ok() {
+                    if let Err(e) = send_funds(
+                        &session,
+                        &market_user,
+                        &transfer.buyer_username,
+                        transfer.refund,
+                    )
+                    .await
+                    {
+                        tracing::error!(
+                            "Could not refund {} escrowed CSH to {} for trade \
+                             {trade_id}, it will be refunded when the market next \
+                             starts: {e}",
+                            transfer.refund,
+                            transfer.buyer_username
+                        );
+
+                        state.read().set_refund_owed(*trade_id, transfer.refund);
+                    }
+                }
+
+                result
+            },
+        };
+
+        let refund = state.write().finish_settlement(*trade_id, result);
+
+        if let Some((buyer_id, amount)) = refund {
+            refund_escrow(state, buyer_id, amount).await;
+        }
+    }
+}
+
+/// Refunds the price improvement of every trade whose refund failed when it
+/// settled.
+async fn refund_owed_trades(state: &GState) {
+    let trade_ids = state
+        .read()
+        .get_trade_ids_where(|trade| trade.get_refund_owed() > 0);
+
+    for trade_id in trade_ids {
+        let taken = state.write().take_refund_owed(trade_id);
+
+        let (buyer_id, amount) = match taken {
+            Ok(owed) => owed,
+            Err(e) => {
+                tracing::error!(
+                    "Could not refund escrowed CSH for trade {trade_id}: {e}"
+                );
+                continue;
+            },
+        };
+
+        match send_refund(state, buyer_id, amount).await {
+            Ok(()) => tracing::info!(
+                "Refunded {amount} escrowed CSH owed to {buyer_id} for trade {trade_id}"
+            ),
+            Err(e) => {
+                tracing::error!(
+                    "Could not refund {amount} escrowed CSH owed to {buyer_id} for \
+                     trade {trade_id}: {e}"
+                );
+
+                state.read().set_refund_owed(trade_id, amount);
+            },
+        }
+    }
+}
+
+/// Settles every trade left pending when the market last stopped, so that the
+/// escrowed funds and reserved items they hold are either paid out or released,
+/// and refunds what is still owed for trades which have settled.
+///
+/// Trades left in flight may already have been paid for, so they are never
+/// paid for again, and have to be checked against the ledger by hand.
+pub(crate) async fn settle_pending_trades(state: &GState) {
+    let (pending, in_flight) = {
+        let state = state.read();
+
+        (
+            state.get_trade_ids_where(|trade| {
+                *trade.get_settlement() == Settlement::Pending
+            }),
+            state.get_trade_ids_where(|trade| {
+                matches!(trade.get_settlement(), Settlement::InFlight { .. })
+            }),
+        )
+    };
+
+    for trade_id in in_flight {
+        tracing::error!(
+            "Trade {trade_id} was being paid for when the market stopped, check the \
+             ledger to see whether it was settled"
+        );
+    }
+
+    if !pending.is_empty() {
+        tracing::info!("Settling {} trade(s) left pending", pending.len());
+
+        settle_trades(state, &pending).await;
+    }
+
+    refund_owed_trades(state).await;
+}
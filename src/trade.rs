@@ -0,0 +1,113 @@
+use crate::{commodity::CommodityUID, offer::OfferUID, user::UserUID};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct TradeUID(pub Uuid);
+
+impl fmt::Display for TradeUID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}", self.0) }
+}
+
+/// The side of the offer which caused a trade by crossing the book.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TradeSide {
+    Buy,
+    Sell,
+}
+
+/// Whether the payment for a trade has been made on the ledger.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Settlement {
+    #[default]
+    Pending,
+    /// The payment was being made when the market last saved, so it may or may
+    /// not have reached the ledger.
+    InFlight {
+        datetime: DateTime<Utc>,
+    },
+    Settled {
+        datetime: DateTime<Utc>,
+    },
+    Failed {
+        reason: String,
+        datetime: DateTime<Utc>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Trade {
+    buyer_id: UserUID,
+    seller_id: UserUID,
+    commodity_id: CommodityUID,
+    ask_id: OfferUID,
+    bid_id: OfferUID,
+    item_amount: u64,
+    price_per_item: u64,
+    /// How much less per item the buyer paid than their bid's price.
+    #[serde(default)]
+    price_improvement: u64,
+    aggressor: TradeSide,
+    datetime: DateTime<Utc>,
+    #[serde(default)]
+    settlement: Settlement,
+    /// Price improvement which couldn't be refunded to the buyer when the trade
+    /// settled, and is still held by the market.
+    #[serde(default)]
+    refund_owed: u64,
+}
+
+impl Trade {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        buyer_id: UserUID,
+        seller_id: UserUID,
+        commodity_id: CommodityUID,
+        ask_id: OfferUID,
+        bid_id: OfferUID,
+        item_amount: u64,
+        price_per_item: u64,
+        price_improvement: u64,
+        aggressor: TradeSide,
+        datetime: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            buyer_id,
+            seller_id,
+            commodity_id,
+            ask_id,
+            bid_id,
+            item_amount,
+            price_per_item,
+            price_improvement,
+            aggressor,
+            datetime,
+            settlement: Settlement::Pending,
+            refund_owed: 0,
+        }
+    }
+
+    pub(crate) fn get_buyer_id(&self) -> UserUID { self.buyer_id }
+    pub(crate) fn get_seller_id(&self) -> UserUID { self.seller_id }
+    pub(crate) fn get_commodity_id(&self) -> CommodityUID { self.commodity_id }
+    pub(crate) fn get_ask_id(&self) -> OfferUID { self.ask_id }
+    pub(crate) fn get_bid_id(&self) -> OfferUID { self.bid_id }
+    pub(crate) fn get_item_amount(&self) -> u64 { self.item_amount }
+    pub(crate) fn get_price_per_item(&self) -> u64 { self.price_per_item }
+    pub(crate) fn get_price_improvement(&self) -> u64 { self.price_improvement }
+    pub(crate) fn get_datetime(&self) -> DateTime<Utc> { self.datetime }
+    pub(crate) fn get_settlement(&self) -> &Settlement { &self.settlement }
+    pub(crate) fn get_refund_owed(&self) -> u64 { self.refund_owed }
+
+    pub(crate) fn set_settlement(&mut self, settlement: Settlement) {
+        self.settlement = settlement;
+    }
+
+    pub(crate) fn set_refund_owed(&mut self, refund_owed: u64) {
+        self.refund_owed = refund_owed;
+    }
+}